@@ -1,13 +1,181 @@
-use std::{io::BufRead, sync::Mutex};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use ardaku::{
+    parse::{self, Reader, Writer},
+    Ardaku, Capability, OpenMode, Portal, Profile,
+};
 use log::Level;
 
+/// Config file the system nickname is persisted to
+const NICKNAME_FILE: &str = "ardaku-nickname.conf";
+/// User database, with one username per line
+const USERS_FILE: &str = "ardaku-users.conf";
+/// Root directory of the application's files
+const FILES_DIR: &str = "ardaku-files";
+/// Root directory of the key-value storage
+const STORE_DIR: &str = "ardaku-store";
+
+/// Encode text as a file name
+fn hex(text: &str) -> String {
+    text.bytes().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Decode a file name encoded with [`hex()`]
+fn unhex(name: &str) -> Option<String> {
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Path of the file storing the value of a key
+fn key_path(namespace: &str, key: &str) -> PathBuf {
+    Path::new(STORE_DIR).join(hex(namespace)).join(hex(key))
+}
+
+/// How a pending prompt reads its input
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Mode {
+    Line,
+    Hidden,
+    Key,
+}
+
+/// Take a complete line (without its line ending) or a single character out
+/// of the input read so far
+fn take_input(buffered: &mut Vec<u8>, mode: Mode) -> Option<String> {
+    let size = match mode {
+        Mode::Line | Mode::Hidden => {
+            buffered.iter().position(|&byte| byte == b'\n')? + 1
+        }
+        Mode::Key => (1..=buffered.len().min(4))
+            .find(|&size| std::str::from_utf8(&buffered[..size]).is_ok())
+            .or((buffered.len() >= 4).then_some(1))?,
+    };
+    let input: Vec<u8> = buffered.drain(..size).collect();
+    let input = String::from_utf8_lossy(&input);
+
+    Some(input.trim_end_matches(['\n', '\r']).into())
+}
+
+/// Write a line read for a prompt to the application's buffer, returning
+/// `false` if the capacity is too small
+fn write_line(
+    bytes: &mut [u8],
+    text: usize,
+    capptr: usize,
+    buffer: &str,
+) -> parse::Result<bool> {
+    let mut reader = Reader::at(bytes, text);
+    let _size = reader.u32()?;
+    let addr = usize::try_from(reader.u32()?).unwrap();
+    let capacity = usize::try_from(Reader::at(bytes, capptr).u32()?).unwrap();
+    let size = buffer.len().try_into().unwrap();
+
+    if capacity < buffer.len() {
+        // Write required capacity to memory
+        Writer::at(bytes, capptr).u32(size)?;
+
+        return Ok(false);
+    }
+
+    log::debug!(target: "demo", "Copying {size} bytes...");
+
+    // Write read line to memory, then its size
+    parse::slice_mut(bytes, addr, buffer.len())?
+        .copy_from_slice(buffer.as_bytes());
+    Writer::at(bytes, text).u32(size)?;
+
+    Ok(true)
+}
+
+/// Run `stty` on the terminal, returning its output if successful
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().into())
+}
+
 struct System {
-    read_line: Mutex<Option<(u32, usize, usize)>>,
+    read_line: Mutex<Option<(u32, usize, usize, Mode)>>,
     pre_queued: Mutex<Option<String>>,
+    // Raw input read from stdin by a background thread
+    input: Mutex<mpsc::Receiver<Vec<u8>>>,
+    // Input received but not yet taken by a prompt
+    buffered: Mutex<Vec<u8>>,
+    // Terminal settings to restore after a hidden or single-key prompt
+    terminal: Mutex<Option<String>>,
+    // Simulated hardware clock, as an offset from the system time in seconds
+    clock_offset: Mutex<i64>,
+    // Simulated performance profile
+    profile: Mutex<Profile>,
+    // Files opened by the application
+    files: Mutex<HashMap<u32, File>>,
+    // Handle of the next file opened
+    next_file: AtomicU32,
 }
 
-// FIXME: Use smelling_salts with whisk channel
+impl System {
+    /// Start a prompt, switching the terminal mode for hidden or single-key
+    /// input
+    fn prompt(&self, ready: u32, index: usize, length: usize, mode: Mode) {
+        let mut terminal = self.terminal.lock().unwrap();
+
+        if mode != Mode::Line && terminal.is_none() {
+            *terminal = stty(&["-g"]);
+
+            match mode {
+                Mode::Hidden => stty(&["-echo"]),
+                _ => stty(&["-icanon", "-echo", "min", "1"]),
+            };
+        }
+
+        *self.read_line.lock().unwrap() = Some((ready, index, length, mode));
+    }
+
+    /// Restore the terminal settings changed for a prompt
+    fn restore_terminal(&self, mode: Mode) {
+        let Some(settings) = self.terminal.lock().unwrap().take() else {
+            return;
+        };
+
+        stty(&[&settings]);
+
+        // The newline wasn't echoed
+        if mode == Mode::Hidden {
+            eprintln!();
+        }
+    }
+}
+
+impl Drop for System {
+    fn drop(&mut self) {
+        self.restore_terminal(Mode::Line);
+    }
+}
 
 impl ardaku::System for System {
     fn sleep(
@@ -15,72 +183,79 @@ impl ardaku::System for System {
         bytes: &mut [u8],
         ready_size: usize,
         ready_data: usize,
+        deadline: Option<u64>,
     ) -> usize {
         log::debug!(target: "demo", "READY DATA: {ready_data:x} ({ready_size})");
 
         log::debug!(target: "demo", "sleep");
 
-        let stdin = std::io::stdin();
-        let mut handle = stdin.lock();
+        let deadline = deadline.map(|deadline| {
+            let timeout = deadline.saturating_sub(self.monotonic());
+
+            Instant::now() + Duration::from_nanos(timeout)
+        });
+        let Some((ready, text, capptr, mode)) = *self.read_line.lock().unwrap()
+        else {
+            // Only timers to wake up for
+            if let Some(deadline) = deadline {
+                thread::sleep(
+                    deadline.saturating_duration_since(Instant::now()),
+                );
+            }
+
+            return 0;
+        };
 
-        // Blocking (FIXME) line reading, returns 1 ready event
+        // Line reading, returns 1 ready event (or 0 if the deadline passed)
         {
-            let buffer =
-                if let Some(buf) = self.pre_queued.lock().unwrap().take() {
-                    buf
-                } else {
-                    let mut buffer = String::with_capacity(1024);
-                    handle.read_line(&mut buffer).unwrap();
-                    if buffer.ends_with('\n') {
-                        buffer.pop();
+            let buffer = if let Some(buf) =
+                self.pre_queued.lock().unwrap().take()
+            {
+                buf
+            } else {
+                let input = self.input.lock().unwrap();
+                let mut buffered = self.buffered.lock().unwrap();
+
+                loop {
+                    if let Some(line) = take_input(&mut buffered, mode) {
+                        break line;
                     }
-                    buffer
-                };
-            let (ready, text, capptr) = self.read_line.lock().unwrap().unwrap();
-            let mut reader = ardaku::parse::Reader::new(&bytes[text..]);
-            let _size = usize::try_from(reader.u32()).unwrap();
-            let addr = usize::try_from(reader.u32()).unwrap();
-            let mut reader = ardaku::parse::Reader::new(&bytes[capptr..]);
-            let capacity = usize::try_from(reader.u32()).unwrap();
-
-            if capacity < buffer.len() {
-                // Write required capacity to memory
-                let mut writer =
-                    ardaku::parse::Writer::new(&mut bytes[capptr..]);
-                let size = buffer.len().try_into().unwrap();
-                writer.u32(size);
 
+                    let chunk = match deadline {
+                        Some(deadline) => input.recv_timeout(
+                            deadline.saturating_duration_since(Instant::now()),
+                        ),
+                        None => input.recv().map_err(RecvTimeoutError::from),
+                    };
+
+                    match chunk {
+                        Ok(chunk) => buffered.extend(chunk),
+                        Err(RecvTimeoutError::Timeout) => return 0,
+                        // Keep reading empty lines at the end of stdin
+                        Err(RecvTimeoutError::Disconnected) => {
+                            let rest = buffered.drain(..).collect();
+
+                            break String::from_utf8(rest).unwrap_or_default();
+                        }
+                    }
+                }
+            };
+            match write_line(bytes, text, capptr, &buffer) {
+                Ok(true) => {}
                 // Store buffer for re-use since WASM doesn't own it yet
-                *self.pre_queued.lock().unwrap() = Some(buffer);
-            } else {
-                // Write size to memory
-                let size = {
-                    let mut writer =
-                        ardaku::parse::Writer::new(&mut bytes[text..]);
-                    let size = buffer.len().min(capacity).try_into().unwrap();
-                    writer.u32(size);
-                    usize::try_from(size).unwrap()
-                };
-
-                log::debug!(target: "demo", "Copying {size} bytes...");
-
-                // Write read line to memory
-                {
-                    let buf = &mut bytes[addr..][..size];
-                    buf.copy_from_slice(buffer.as_bytes());
+                Ok(false) => *self.pre_queued.lock().unwrap() = Some(buffer),
+                Err(error) => {
+                    log::warn!(target: "demo", "Dropped line: {error}");
                 }
             }
 
             log::debug!(target: "demo", "Add to ready list");
 
-            // Add to ready list
-            {
-                let ready_list = &mut bytes[ready_data..][..ready_size * 4];
-                let mut writer = ardaku::parse::Writer::new(ready_list);
-                writer.u32(ready);
-            }
+            *self.read_line.lock().unwrap() = None;
+            self.restore_terminal(mode);
 
-            1
+            // Add to ready list
+            usize::from(Writer::at(bytes, ready_data).u32(ready).is_ok())
         }
     }
 
@@ -89,8 +264,187 @@ impl ardaku::System for System {
     }
 
     fn read_line(&self, ready: u32, index: usize, length: usize) {
-        let mut read_line = self.read_line.lock().unwrap();
-        *read_line = Some((ready, index, length));
+        self.prompt(ready, index, length, Mode::Line);
+    }
+
+    fn show_prompt(&self, label: &str) {
+        let mut stderr = std::io::stderr();
+
+        let _ = write!(stderr, "{label}");
+        let _ = stderr.flush();
+    }
+
+    fn read_hidden(&self, ready: u32, index: usize, length: usize) {
+        self.prompt(ready, index, length, Mode::Hidden);
+    }
+
+    fn read_key(&self, ready: u32, index: usize, length: usize) {
+        self.prompt(ready, index, length, Mode::Key);
+    }
+
+    fn username(&self) -> String {
+        std::env::var("USER").unwrap_or_else(|_| "user".into())
+    }
+
+    fn hostname(&self) -> String {
+        std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|hostname| hostname.trim().into())
+            .unwrap_or_else(|_| "localhost".into())
+    }
+
+    fn nickname(&self) -> String {
+        std::fs::read_to_string(NICKNAME_FILE)
+            .map(|nickname| nickname.trim().into())
+            .unwrap_or_else(|_| self.hostname())
+    }
+
+    fn set_nickname(&self, nickname: &str) -> bool {
+        std::fs::write(NICKNAME_FILE, nickname).is_ok()
+    }
+
+    fn random(&self, buffer: &mut [u8]) -> bool {
+        std::fs::File::open("/dev/urandom")
+            .and_then(|mut urandom| urandom.read_exact(buffer))
+            .is_ok()
+    }
+
+    fn hardware_clock(&self) -> Option<u64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        let offset = *self.clock_offset.lock().unwrap();
+
+        now.as_secs().checked_add_signed(offset)
+    }
+
+    fn set_hardware_clock(&self, secs: u64) -> bool {
+        let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) else {
+            return false;
+        };
+        let Ok(secs) = i64::try_from(secs) else {
+            return false;
+        };
+        let Ok(now) = i64::try_from(now.as_secs()) else {
+            return false;
+        };
+
+        *self.clock_offset.lock().unwrap() = secs - now;
+        true
+    }
+
+    fn set_profile(&self, profile: Profile) -> bool {
+        log::info!(target: "demo", "Simulated performance profile: {profile:?}");
+        *self.profile.lock().unwrap() = profile;
+        true
+    }
+
+    fn open_file(&self, path: &str, mode: OpenMode) -> Option<u32> {
+        let path = Path::new(FILES_DIR).join(path);
+        let mut options = OpenOptions::new();
+
+        match mode {
+            OpenMode::Read => options.read(true),
+            OpenMode::Write => options.write(true).create(true).truncate(true),
+            OpenMode::Append => options.append(true).create(true),
+        };
+
+        if mode != OpenMode::Read {
+            std::fs::create_dir_all(path.parent()?).ok()?;
+        }
+
+        let file = options.open(path).ok()?;
+        let handle = self.next_file.fetch_add(1, Ordering::Relaxed);
+
+        self.files.lock().unwrap().insert(handle, file);
+
+        Some(handle)
+    }
+
+    fn close_file(&self, file: u32) {
+        self.files.lock().unwrap().remove(&file);
+    }
+
+    fn read_file(&self, file: u32, buffer: &mut [u8]) -> Option<usize> {
+        self.files.lock().unwrap().get_mut(&file)?.read(buffer).ok()
+    }
+
+    fn write_file(&self, file: u32, data: &[u8]) -> Option<usize> {
+        self.files.lock().unwrap().get_mut(&file)?.write(data).ok()
+    }
+
+    fn list_files(&self, path: &str) -> Option<Vec<String>> {
+        std::fs::read_dir(Path::new(FILES_DIR).join(path))
+            .ok()?
+            .map(|entry| {
+                Some(entry.ok()?.file_name().to_string_lossy().into_owned())
+            })
+            .collect()
+    }
+
+    fn remove_file(&self, path: &str) -> bool {
+        std::fs::remove_file(Path::new(FILES_DIR).join(path)).is_ok()
+    }
+
+    fn get_value(&self, namespace: &str, key: &str) -> Option<Vec<u8>> {
+        std::fs::read(key_path(namespace, key)).ok()
+    }
+
+    fn put_value(&self, namespace: &str, key: &str, value: &[u8]) -> bool {
+        let path = key_path(namespace, key);
+        // Write to a temporary file and rename it, so writes are atomic
+        let temp = path.with_extension("tmp");
+
+        path.parent()
+            .is_some_and(|dir| std::fs::create_dir_all(dir).is_ok())
+            && std::fs::write(&temp, value).is_ok()
+            && std::fs::rename(temp, path).is_ok()
+    }
+
+    fn delete_value(&self, namespace: &str, key: &str) -> bool {
+        std::fs::remove_file(key_path(namespace, key)).is_ok()
+    }
+
+    fn list_keys(&self, namespace: &str, prefix: &str) -> Option<Vec<String>> {
+        let Ok(entries) =
+            std::fs::read_dir(Path::new(STORE_DIR).join(hex(namespace)))
+        else {
+            return Some(Vec::new());
+        };
+        let mut keys: Vec<String> = entries
+            .filter_map(|entry| unhex(entry.ok()?.file_name().to_str()?))
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+
+        keys.sort();
+
+        Some(keys)
+    }
+
+    fn users(&self) -> Vec<String> {
+        std::fs::read_to_string(USERS_FILE)
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    fn create_user(&self, username: &str) -> bool {
+        let mut users = self.users();
+
+        if users.iter().any(|user| user == username) {
+            return false;
+        }
+
+        users.push(username.into());
+        std::fs::write(USERS_FILE, users.join("\n")).is_ok()
+    }
+
+    fn delete_user(&self, username: &str) -> bool {
+        let mut users = self.users();
+        let count = users.len();
+
+        users.retain(|user| user != username);
+
+        users.len() != count
+            && std::fs::write(USERS_FILE, users.join("\n")).is_ok()
     }
 }
 
@@ -98,14 +452,52 @@ fn main() -> ardaku::engine::Result {
     // Setup
     env_logger::init();
 
-    let app_path = std::env::args().skip(1).next().expect("Provide wasm file!");
-    let exe = std::fs::read(app_path).expect("Couldn't find file!");
+    let app_path = std::env::args().nth(1).expect("Provide wasm file!");
+    let exe = std::fs::read(&app_path).expect("Couldn't find file!");
+    let namespace = Path::new(&app_path)
+        .file_stem()
+        .map_or("app".into(), |stem| stem.to_string_lossy().into_owned());
+
+    let (sender, input) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut chunk = [0; 256];
+
+        while let Ok(size @ 1..) = stdin.read(&mut chunk) {
+            if sender.send(chunk[..size].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
 
     // Run app
     let system = System {
         read_line: Mutex::new(None),
         pre_queued: Mutex::new(None),
+        input: Mutex::new(input),
+        buffered: Mutex::new(Vec::new()),
+        terminal: Mutex::new(None),
+        clock_offset: Mutex::new(0),
+        profile: Mutex::new(Profile::Balanced),
+        files: Mutex::new(HashMap::new()),
+        next_file: AtomicU32::new(0),
     };
 
-    ardaku::run(system, &exe)
+    let report = Ardaku::builder()
+        .capability(Portal::System, Capability::Allow)
+        .capability(Portal::Hardware, Capability::Allow)
+        .capability(Portal::Admin, Capability::Allow)
+        .namespace(namespace)
+        .args(std::env::args().skip(1))
+        .build()
+        .run(system, &exe)?;
+
+    log::info!(target: "demo", "Exited: {report:?}");
+
+    if report.exit_status != 0 {
+        std::process::exit(report.exit_status);
+    }
+
+    Ok(())
 }
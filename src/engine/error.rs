@@ -1,6 +1,11 @@
 //! Errors
 
-use wasmi::core::Trap;
+use alloc::string::String;
+use core::{fmt, str::Utf8Error};
+
+use wasmi::core::{HostError, Trap, TrapCode};
+
+use crate::parse::OutOfBounds;
 
 /// WebAssembly Engine Result
 pub type Result<T = (), E = Error> = core::result::Result<T, E>;
@@ -14,8 +19,129 @@ pub enum Error {
     LinkerFailed,
     /// Application has crashed from one of the various traps
     Crash(Trap),
-    /// Application does not export "ardaku" memory.
+    /// Application does not export its memory
     MissingMemory,
-    /// "run" function not exported
+    /// Entry point function not exported
     MissingRun,
+    /// Application tried to connect to a portal ID that doesn't exist
+    InvalidPortal(u32),
+    /// Application sent a command on a channel that isn't connected
+    InvalidChannel(u32),
+    /// Application sent a command that its portal doesn't have
+    InvalidCommand(u32),
+    /// Application sent a command with the wrong size for its portal
+    BadCommandSize {
+        /// Command sizes the portal accepts (in bytes)
+        expected: &'static [u32],
+        /// Command size the application sent (in bytes)
+        found: u32,
+    },
+    /// Application passed text that isn't valid UTF-8
+    InvalidUtf8(Utf8Error),
+    /// Application logged with a level that doesn't exist
+    InvalidLogLevel(u8),
+    /// Application requested a performance profile that doesn't exist
+    InvalidProfile(u32),
+    /// Application opened a file with a mode that doesn't exist
+    InvalidOpenMode(u32),
+    /// Application wrote to an output stream that doesn't exist
+    InvalidStream(u32),
+    /// Application queued more messages on its MPMC channels than the limit
+    ChannelFull(u32),
+    /// Application passed a pointer outside of its memory
+    OutOfBounds(OutOfBounds),
+    /// Application panicked with a fatal log message
+    GuestPanic {
+        /// The log target of the fatal message
+        target: String,
+        /// The panic message
+        message: String,
+    },
+    /// Application ran out of fuel before it finished
+    OutOfFuel,
+    /// Application's initial memory is larger than the memory limit
+    OutOfMemory,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidWasm => write!(f, "invalid wasm"),
+            Self::LinkerFailed => write!(f, "linker failed"),
+            Self::Crash(trap) => write!(f, "crash: {trap}"),
+            Self::MissingMemory => write!(f, "missing memory export"),
+            Self::MissingRun => write!(f, "missing run export"),
+            Self::InvalidPortal(portal) => write!(f, "invalid portal {portal}"),
+            Self::InvalidChannel(channel) => {
+                write!(f, "invalid channel {channel}")
+            }
+            Self::InvalidCommand(command) => {
+                write!(f, "invalid command {command}")
+            }
+            Self::BadCommandSize { expected, found } => {
+                write!(f, "bad command size {found} (expected ")?;
+
+                for (i, size) in expected.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, " or ")?;
+                    }
+
+                    write!(f, "{size}")?;
+                }
+
+                write!(f, ")")
+            }
+            Self::InvalidUtf8(error) => write!(f, "invalid utf8: {error}"),
+            Self::InvalidLogLevel(level) => {
+                write!(f, "invalid log level {:?}", char::from(*level))
+            }
+            Self::InvalidProfile(profile) => {
+                write!(f, "invalid performance profile {profile}")
+            }
+            Self::InvalidOpenMode(mode) => {
+                write!(f, "invalid file open mode {mode}")
+            }
+            Self::InvalidStream(stream) => {
+                write!(f, "invalid output stream {stream}")
+            }
+            Self::ChannelFull(channel) => {
+                write!(f, "too many messages queued on channel {channel}")
+            }
+            Self::OutOfBounds(error) => write!(f, "{error}"),
+            Self::GuestPanic { target, message } => {
+                write!(f, "guest panicked ({target}): {message}")
+            }
+            Self::OutOfFuel => write!(f, "out of fuel"),
+            Self::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+impl HostError for Error {}
+
+impl From<Trap> for Error {
+    fn from(trap: Trap) -> Self {
+        if matches!(trap.trap_code(), Some(TrapCode::OutOfFuel)) {
+            return Self::OutOfFuel;
+        }
+
+        // Host errors are passed through the trap to the caller of `run()`
+        if trap.downcast_ref::<Self>().is_none() {
+            return Self::Crash(trap);
+        }
+
+        trap.downcast().expect("checked by downcast_ref")
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(error: Utf8Error) -> Self {
+        Self::InvalidUtf8(error)
+    }
+}
+
+impl From<OutOfBounds> for Error {
+    fn from(error: OutOfBounds) -> Self {
+        Self::OutOfBounds(error)
+    }
 }
@@ -1,16 +1,43 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+/// Log per-command tracing, if enabled with [`Builder::trace()`]
+macro_rules! trace {
+    ($trace:expr, $($arg:tt)*) => {
+        if $trace {
+            log::trace!(target: "ardaku", $($arg)*);
+        }
+    };
+}
 
 pub mod engine;
 pub mod parse;
-
-use alloc::vec::Vec;
-use core::mem::MaybeUninit;
+mod portal;
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    rc::Rc,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
 use log::Level;
-use wasmi::{Caller, Extern, Func, Linker, Memory, Module, Store};
+use wasmi::{
+    core::{Trap, TrapCode},
+    errors::{MemoryError, TableError},
+    Caller, Extern, Func, Linker, Memory, Module, ResourceLimiter, Store,
+    WasmParams, WasmResults,
+};
 
+pub use self::portal::MemoryStore;
 use self::{
     engine::{Error, Result as EngineResult},
     parse::{Reader, Writer},
@@ -18,16 +45,25 @@ use self::{
 
 /// The system should implement these syscalls
 pub trait System {
-    /// Sleep until some event(s) happen.
+    /// Sleep until some event(s) happen, or the monotonic time (see
+    /// [`System::monotonic()`]) reaches `deadline`.
     ///
     /// # Parameters
     ///  - `bytes`: Slice of bytes of WebAssembly module memory
     ///  - `size`: Capacity for number of `u32`s in ready list
     ///  - `data`: Pointer in bytes to ready list
+    ///  - `deadline`: Earliest pending timer deadline, if any (or an earlier
+    ///    time to check on running blocking tasks)
     ///
     /// # Returns
     ///  - Length of overwritten ready list
-    fn sleep(&self, bytes: &mut [u8], size: usize, data: usize) -> usize;
+    fn sleep(
+        &self,
+        bytes: &mut [u8],
+        size: usize,
+        data: usize,
+        deadline: Option<u64>,
+    ) -> usize;
 
     /// Write a message to the logs.
     ///
@@ -49,19 +85,571 @@ pub trait System {
     ///  - `data`: Pointer to the UTF-8 buffer (`size: u32`, `reference: u32`)
     ///  - `size`: Pointer to the capacity of the UTF-8 buffer (in bytes)
     fn read_line(&self, ready: u32, data: usize, size: usize);
+
+    /// Show a label before reading input for the prompt portal.
+    ///
+    /// Does nothing by default.
+    fn show_prompt(&self, label: &str) {
+        let _ = label;
+    }
+
+    /// Spawn task of reading a line, like [`System::read_line()`], without
+    /// echoing the input (for passwords).
+    ///
+    /// Defaults to [`System::read_line()`], which may echo the input.
+    fn read_hidden(&self, ready: u32, data: usize, size: usize) {
+        self.read_line(ready, data, size)
+    }
+
+    /// Spawn task of reading a single key press (one UTF-8 character), like
+    /// [`System::read_line()`], without waiting for a newline.
+    ///
+    /// Defaults to [`System::read_line()`].
+    fn read_key(&self, ready: u32, data: usize, size: usize) {
+        self.read_line(ready, data, size)
+    }
+
+    /// Ask whether the application may connect to a portal with the
+    /// [`Capability::Ask`] policy.  The answer is remembered until the
+    /// application exits.
+    ///
+    /// Denies by default.
+    ///
+    /// # Parameters
+    ///  - `portal`: The portal the application is connecting to
+    fn ask(&self, portal: Portal) -> bool {
+        let _ = portal;
+
+        false
+    }
+
+    /// Get the current user's username.
+    ///
+    /// Default is `"user"`.
+    fn username(&self) -> String {
+        "user".into()
+    }
+
+    /// Get the current user's display name.
+    ///
+    /// Defaults to the username.
+    fn display_name(&self) -> String {
+        self.username()
+    }
+
+    /// Set the current user's display name.
+    ///
+    /// Fails by default.
+    ///
+    /// # Returns
+    ///  - `true` if the display name was changed
+    fn set_display_name(&self, name: &str) -> bool {
+        let _ = name;
+
+        false
+    }
+
+    /// Get the system's human-friendly nickname.
+    ///
+    /// Defaults to the hostname.
+    fn nickname(&self) -> String {
+        self.hostname()
+    }
+
+    /// Get the system's hostname.
+    ///
+    /// Default is `"localhost"`.
+    fn hostname(&self) -> String {
+        "localhost".into()
+    }
+
+    /// Set the system's human-friendly nickname.
+    ///
+    /// Fails by default.
+    ///
+    /// # Returns
+    ///  - `true` if the nickname was changed
+    fn set_nickname(&self, nickname: &str) -> bool {
+        let _ = nickname;
+
+        false
+    }
+
+    /// Set the system's hostname.
+    ///
+    /// Fails by default.
+    ///
+    /// # Returns
+    ///  - `true` if the hostname was changed
+    fn set_hostname(&self, hostname: &str) -> bool {
+        let _ = hostname;
+
+        false
+    }
+
+    /// Get the specs of the hardware the system is running on.
+    ///
+    /// With the `std` feature, reads `/proc` by default (on Linux).  Otherwise
+    /// defaults to unknown values.
+    fn platform(&self) -> Platform {
+        portal::detect_platform()
+    }
+
+    /// Read the hardware clock (in seconds since the Unix epoch).
+    ///
+    /// Unavailable by default.
+    fn hardware_clock(&self) -> Option<u64> {
+        None
+    }
+
+    /// Set the hardware clock (in seconds since the Unix epoch).
+    ///
+    /// Fails by default.
+    ///
+    /// # Returns
+    ///  - `true` if the hardware clock was set
+    fn set_hardware_clock(&self, secs: u64) -> bool {
+        let _ = secs;
+
+        false
+    }
+
+    /// Request a performance profile.
+    ///
+    /// Fails by default.
+    ///
+    /// # Returns
+    ///  - `true` if the performance profile was applied
+    fn set_profile(&self, profile: Profile) -> bool {
+        let _ = profile;
+
+        false
+    }
+
+    /// Get the monotonic time (in nanoseconds since an arbitrary point).
+    ///
+    /// With the `std` feature, defaults to the time since it was first called.
+    /// Otherwise defaults to `0`, so timers never fire.
+    fn monotonic(&self) -> u64 {
+        portal::monotonic()
+    }
+
+    /// Get the wall-clock time (in nanoseconds since the Unix epoch).
+    ///
+    /// With the `std` feature, defaults to the system time.  Otherwise
+    /// defaults to `0`.
+    fn wall_clock(&self) -> u64 {
+        portal::wall_clock()
+    }
+
+    /// Fill `buffer` with cryptographically secure random bytes.
+    ///
+    /// Unavailable by default.
+    ///
+    /// # Returns
+    ///  - `true` if `buffer` was filled
+    fn random(&self, buffer: &mut [u8]) -> bool {
+        let _ = buffer;
+
+        false
+    }
+
+    /// Open a file, returning its handle.
+    ///
+    /// Paths passed to file methods are `/`-separated, relative to the
+    /// application's root directory, and never contain `..` components.
+    ///
+    /// Fails by default.
+    fn open_file(&self, path: &str, mode: OpenMode) -> Option<u32> {
+        let _ = (path, mode);
+
+        None
+    }
+
+    /// Close a file opened with [`System::open_file()`].
+    fn close_file(&self, file: u32) {
+        let _ = file;
+    }
+
+    /// Read from a file into `buffer`, returning the number of bytes read.
+    ///
+    /// Fails by default.
+    fn read_file(&self, file: u32, buffer: &mut [u8]) -> Option<usize> {
+        let _ = (file, buffer);
+
+        None
+    }
+
+    /// Write `data` to a file, returning the number of bytes written.
+    ///
+    /// Fails by default.
+    fn write_file(&self, file: u32, data: &[u8]) -> Option<usize> {
+        let _ = (file, data);
+
+        None
+    }
+
+    /// List the names of the files in a directory.
+    ///
+    /// Fails by default.
+    fn list_files(&self, path: &str) -> Option<Vec<String>> {
+        let _ = path;
+
+        None
+    }
+
+    /// Remove a file.
+    ///
+    /// Fails by default.
+    ///
+    /// # Returns
+    ///  - `true` if the file was removed
+    fn remove_file(&self, path: &str) -> bool {
+        let _ = path;
+
+        false
+    }
+
+    /// Get the value of a key in an application's key-value storage.
+    ///
+    /// Key-value storage is namespaced per application (see
+    /// [`Builder::namespace()`]).  [`MemoryStore`] can be used to implement
+    /// the key-value methods without persistent storage.
+    ///
+    /// Fails by default.
+    fn get_value(&self, namespace: &str, key: &str) -> Option<Vec<u8>> {
+        let _ = (namespace, key);
+
+        None
+    }
+
+    /// Set the value of a key in an application's key-value storage.
+    ///
+    /// Fails by default.
+    ///
+    /// # Returns
+    ///  - `true` if the value was stored
+    fn put_value(&self, namespace: &str, key: &str, value: &[u8]) -> bool {
+        let _ = (namespace, key, value);
+
+        false
+    }
+
+    /// Delete a key from an application's key-value storage.
+    ///
+    /// Fails by default.
+    ///
+    /// # Returns
+    ///  - `true` if the key was deleted
+    fn delete_value(&self, namespace: &str, key: &str) -> bool {
+        let _ = (namespace, key);
+
+        false
+    }
+
+    /// List the keys starting with `prefix` in an application's key-value
+    /// storage.
+    ///
+    /// Fails by default.
+    fn list_keys(&self, namespace: &str, prefix: &str) -> Option<Vec<String>> {
+        let _ = (namespace, prefix);
+
+        None
+    }
+
+    /// Write raw bytes to an output stream.
+    ///
+    /// With the `std` feature, defaults to the process's standard output or
+    /// error.  Otherwise discards the bytes by default.
+    fn write_output(&self, stream: Stream, data: &[u8]) {
+        portal::write_output(stream, data)
+    }
+
+    /// List the usernames of all user accounts.
+    ///
+    /// Defaults to only the current user.
+    fn users(&self) -> Vec<String> {
+        alloc::vec![self.username()]
+    }
+
+    /// Create a user account.
+    ///
+    /// Fails by default.
+    ///
+    /// # Returns
+    ///  - `true` if the user account was created
+    fn create_user(&self, username: &str) -> bool {
+        let _ = username;
+
+        false
+    }
+
+    /// Delete a user account.
+    ///
+    /// Fails by default.
+    ///
+    /// # Returns
+    ///  - `true` if the user account was deleted
+    fn delete_user(&self, username: &str) -> bool {
+        let _ = username;
+
+        false
+    }
+}
+
+/// Channel ID written back to the application in place of a portal it isn't
+/// allowed to connect to.  Sending a command on this channel traps.
+pub const DENIED_CHANNEL: u32 = u32::MAX;
+
+/// Policy for whether an application may connect to a portal
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Capability {
+    /// Connecting to the portal is allowed
+    Allow,
+    /// Connecting to the portal is denied
+    Deny,
+    /// Ask the [`System`] when the application connects to the portal
+    Ask,
+}
+
+/// Resource usage of an application that ran to completion
+#[derive(Debug, Copy, Clone)]
+pub struct Report {
+    /// Amount of fuel consumed, or `None` if fuel metering was disabled
+    pub fuel_consumed: Option<u64>,
+    /// Largest number of 64 kB pages the application's memory grew to
+    pub peak_pages: u32,
+    /// Exit status reported through [`Portal::Exit`], or `0` if the
+    /// application returned from its entry point
+    pub exit_status: i32,
+}
+
+/// Specs of the hardware the system is running on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    /// CPU model name, or `"unknown"`
+    pub cpu_model: String,
+    /// Number of logical CPU cores, or `0` if unknown
+    pub cores: u32,
+    /// Total memory (in bytes), or `0` if unknown
+    pub memory: u64,
+    /// CPU architecture, or `"unknown"`
+    pub arch: String,
+    /// Whether a GPU is present
+    pub gpu: bool,
+}
+
+impl Default for Platform {
+    fn default() -> Self {
+        Self {
+            cpu_model: "unknown".into(),
+            cores: 0,
+            memory: 0,
+            arch: "unknown".into(),
+            gpu: false,
+        }
+    }
+}
+
+/// Performance profile requested through [`Portal::Hardware`]
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Profile {
+    /// Save power at the cost of performance
+    PowerSave = 0,
+    /// Balance performance and power usage
+    Balanced = 1,
+    /// Maximize performance at the cost of power usage
+    Performance = 2,
+}
+
+impl Profile {
+    /// Look up a performance profile from its ID
+    pub fn from_id(id: u32) -> Option<Self> {
+        Some(match id {
+            0 => Self::PowerSave,
+            1 => Self::Balanced,
+            2 => Self::Performance,
+            _ => return None,
+        })
+    }
+}
+
+/// How to open a file through [`Portal::File`]
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Open an existing file for reading
+    Read = 0,
+    /// Create a file, or truncate an existing one, for writing
+    Write = 1,
+    /// Create a file, or open an existing one, for writing at its end
+    Append = 2,
+}
+
+impl OpenMode {
+    /// Look up an open mode from its ID
+    pub fn from_id(id: u32) -> Option<Self> {
+        Some(match id {
+            0 => Self::Read,
+            1 => Self::Write,
+            2 => Self::Append,
+            _ => return None,
+        })
+    }
+}
+
+/// Output stream written to through [`Portal::Output`]
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stream {
+    /// Standard output
+    Stdout = 0,
+    /// Standard error
+    Stderr = 1,
+}
+
+impl Stream {
+    /// Look up an output stream from its ID
+    pub fn from_id(id: u32) -> Option<Self> {
+        Some(match id {
+            0 => Self::Stdout,
+            1 => Self::Stderr,
+            _ => return None,
+        })
+    }
+}
+
+/// Memory growth limiter, keeping track of the high-water mark.  The limit is
+/// shared by the whole task tree.
+struct Limiter {
+    max_pages: Option<u32>,
+    peak_pages: u32,
+    // Pages allocated by this task
+    pages: u32,
+    // Pages allocated by the whole task tree
+    tree_pages: Arc<AtomicU32>,
+    // Pages added by the last memory growth, and the peak before it, in case
+    // it fails
+    grown: u32,
+    previous_peak: u32,
+    // Set if a memory allocation was denied
+    denied: bool,
+}
+
+impl Limiter {
+    /// Make a limiter sharing `tree_pages` with the rest of the task tree
+    fn new(max_pages: Option<u32>, tree_pages: Arc<AtomicU32>) -> Self {
+        Self {
+            max_pages,
+            peak_pages: 0,
+            pages: 0,
+            tree_pages,
+            grown: 0,
+            previous_peak: 0,
+            denied: false,
+        }
+    }
+}
+
+impl ResourceLimiter for Limiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool, MemoryError> {
+        let pages = u32::try_from(desired / 65_536).unwrap_or(u32::MAX);
+        let grown = pages.saturating_sub(self.pages);
+        let reserved = self.tree_pages.fetch_update(
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+            |tree_pages| {
+                let tree_pages = tree_pages.saturating_add(grown);
+
+                if self.max_pages.is_some_and(|max| tree_pages > max) {
+                    return None;
+                }
+
+                Some(tree_pages)
+            },
+        );
+
+        if reserved.is_err() {
+            log::warn!(target: "ardaku", "Denied memory growth to {pages} pages");
+            self.denied = true;
+            return Ok(false);
+        }
+
+        self.pages = pages;
+        self.grown = grown;
+        self.previous_peak = self.peak_pages;
+        self.peak_pages = self.peak_pages.max(pages);
+
+        Ok(true)
+    }
+
+    fn memory_grow_failed(&mut self, _error: &MemoryError) {
+        self.tree_pages.fetch_sub(self.grown, Ordering::SeqCst);
+        self.pages -= self.grown;
+        self.grown = 0;
+        self.peak_pages = self.previous_peak;
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        _desired: u32,
+        _maximum: Option<u32>,
+    ) -> Result<bool, TableError> {
+        Ok(true)
+    }
+}
+
+impl Drop for Limiter {
+    fn drop(&mut self) {
+        // Return this task's pages to the task tree
+        self.tree_pages.fetch_sub(self.pages, Ordering::SeqCst);
+    }
 }
 
 struct State<S: System> {
     memory: MaybeUninit<Memory>,
-    system: S,
+    limiter: Limiter,
+    system: Rc<S>,
+    task: Task,
     ready_list: (u32, u32),
-    portals: [bool; Portal::Max as u32 as usize],
+    // Ready IDs of commands completed by the host, not yet in the ready list
+    ready_queue: VecDeque<u32>,
+    // Policy for which portals the application may connect to
+    capabilities: [Capability; Portal::COUNT],
+    // Log each command sent by the application
+    trace: bool,
+    portals: [bool; Portal::COUNT],
     // Channel IDs that can be reclaimed
     drop_channels: Vec<u32>,
     // Next channel ID
     next_channel: u32,
     // Connected channels
     conn_channels: Vec<Option<ConnectedChannel<S>>>,
+    // Resources shared by the task tree
+    tree: Rc<portal::Tree>,
+    // Handle of the next spawned child task
+    next_task: u32,
+    // Blocking tasks spawned by the application
+    blocking: portal::Blocking,
+    // Number of commands waiting on the system to complete them
+    system_pending: usize,
+    // Pending timers
+    timers: portal::Timers,
+    // Deterministic random number generator, if seeded by the embedder
+    seeded: Option<portal::Seeded>,
+    // Handles of files opened by the application
+    files: Vec<u32>,
+    // Exit status, once the application has requested to exit
+    exit_status: Option<i32>,
+    // Fuel left for child tasks spawned by the current request
+    fuel: Option<u64>,
 }
 
 /// Command
@@ -84,8 +672,8 @@ struct Connect {
 
 /// Portal IDs
 #[repr(u32)]
-#[derive(Debug, Copy, Clone)]
-enum Portal {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Portal {
     /// Logging API (stdout/printf)
     Log = 0,
     /// Developer command API (stdin/scanf)
@@ -110,112 +698,192 @@ enum Portal {
     Channel,
     /// Account API (create / delete users)
     Admin,
-    ///
-    Max,
+    /// Timer API (monotonic / wall-clock time, timers)
+    Timer,
+    /// Entropy API (random bytes)
+    Entropy,
+    /// Filesystem API (sandboxed to a directory)
+    File,
+    /// Key-value storage API
+    Store,
+    /// Raw output stream API (stdout / stderr)
+    Output,
+    /// Arguments and environment API
+    Args,
+    /// Exit API (exit status)
+    Exit,
 }
 
-struct ConnectedChannel<S: System> {
-    portal: Portal,
-    callback: fn(&mut S, u32, &mut [u8], u32, u32) -> bool,
+impl Portal {
+    /// Number of portals
+    const COUNT: usize = Self::Exit as usize + 1;
+
+    /// Look up a portal from its ID
+    pub fn from_id(id: u32) -> Option<Self> {
+        Some(match id {
+            0 => Self::Log,
+            1 => Self::Prompt,
+            2 => Self::Account,
+            3 => Self::User,
+            4 => Self::System,
+            5 => Self::Host,
+            6 => Self::Hardware,
+            7 => Self::Platform,
+            8 => Self::Spawn,
+            9 => Self::SpawnBlocking,
+            10 => Self::Channel,
+            11 => Self::Admin,
+            12 => Self::Timer,
+            13 => Self::Entropy,
+            14 => Self::File,
+            15 => Self::Store,
+            16 => Self::Output,
+            17 => Self::Args,
+            18 => Self::Exit,
+            _ => return None,
+        })
+    }
 }
 
-fn fixme<S: System>(_: &mut S, _: u32, _: &mut [u8], _: u32, _: u32) -> bool {
-    log::error!(target: "ardaku", "FIXME");
+/// Portal command handler, returns `true` if completed without waiting
+type Callback<S> =
+    fn(&mut State<S>, u32, &mut [u8], u32, u32) -> EngineResult<bool>;
 
-    true
+struct ConnectedChannel<S: System> {
+    portal: Portal,
+    callback: Callback<S>,
 }
 
+/// Prompt command (8 bytes): `capacity: u32` - pointer to the buffer
+/// capacity, `buffer: u32` - pointer to the text buffer (size and data).
+///
+/// Extended prompt command (20 bytes) starts with `mode: u32` (`0` for a line,
+/// `1` for a hidden line, `2` for a single key) and `label_size: u32`,
+/// `label_data: u32` (UTF-8 text shown before reading, or size 0 for none).
 fn prompt<S: System>(
-    system: &mut S,
+    state: &mut State<S>,
     ready: u32,
     bytes: &mut [u8],
     size: u32,
     data: u32,
-) -> bool {
-    log::trace!(target: "ardaku", "prompt");
+) -> EngineResult<bool> {
+    trace!(state.trace, "prompt");
+    trace!(state.trace, "prompt size: {size}, data: {data}");
 
-    let size: usize = size.try_into().unwrap();
     let data: usize = data.try_into().unwrap();
+    let mut prompt_cmd = Reader::at(bytes, data);
+
+    // Extended prompt commands start with the mode and label
+    let (mode, label) = match size {
+        8 => (0, ""),
+        20 => {
+            let mode = prompt_cmd.u32()?;
+            let label_size: usize = prompt_cmd.u32()?.try_into().unwrap();
+            let label_data: usize = prompt_cmd.u32()?.try_into().unwrap();
+            let label = parse::slice(bytes, label_data, label_size)?;
+            let label = core::str::from_utf8(label)?;
+
+            if mode > 2 {
+                return Err(Error::InvalidCommand(mode));
+            }
 
-    log::trace!(target: "ardaku", "prompt size: {size}, data: {data}");
+            (mode, label)
+        }
+        _ => {
+            return Err(Error::BadCommandSize {
+                expected: &[8, 20],
+                found: size,
+            })
+        }
+    };
+    let capacity_ref: usize = prompt_cmd.u32()?.try_into().unwrap();
+    let text_ref: usize = prompt_cmd.u32()?.try_into().unwrap();
 
-    if size != 8 {
-        todo!("Host trap: command size {size}");
-    }
+    // The system writes through these later, so check them now
+    parse::slice(bytes, capacity_ref, 4)?;
+    parse::slice(bytes, text_ref, 8)?;
 
-    let mut prompt_cmd = Reader::new(&bytes[data..][..size]);
-    let capacity_ref: usize = prompt_cmd.u32().try_into().unwrap();
-    let text_ref: usize = prompt_cmd.u32().try_into().unwrap();
+    trace!(state.trace, "prompt readline (mode {mode})");
 
-    log::trace!(target: "ardaku", "prompt readline");
+    if !label.is_empty() {
+        state.system.show_prompt(label);
+    }
 
-    system.read_line(ready, text_ref, capacity_ref);
+    match mode {
+        0 => state.system.read_line(ready, text_ref, capacity_ref),
+        1 => state.system.read_hidden(ready, text_ref, capacity_ref),
+        _ => state.system.read_key(ready, text_ref, capacity_ref),
+    }
+    state.system_pending += 1;
 
-    false
+    Ok(false)
 }
 
 fn log<S: System>(
-    system: &mut S,
+    state: &mut State<S>,
     _ready: u32,
     bytes: &mut [u8],
     size: u32,
     data: u32,
-) -> bool {
-    let size: usize = size.try_into().unwrap();
-    let data: usize = data.try_into().unwrap();
-
+) -> EngineResult<bool> {
     if size != 16 {
-        todo!("Host trap: command size {size}");
+        return Err(Error::BadCommandSize {
+            expected: &[16],
+            found: size,
+        });
     }
 
-    let mut log_cmd = Reader::new(&bytes[data..][..size]);
-    let message_size: usize = log_cmd.u32().try_into().unwrap();
-    let message_data: usize = log_cmd.u32().try_into().unwrap();
-    let target_size: usize = log_cmd.u32().try_into().unwrap();
-    let target_data: usize = log_cmd.u32().try_into().unwrap();
+    let data: usize = data.try_into().unwrap();
 
-    log::trace!(target: "ardaku", "Message (data, size) = ({target_data}, {target_size})");
-    let target = &bytes[target_data..][..usize::from(target_size)];
-    let target = if let Ok(target) = core::str::from_utf8(target) {
-        target
-    } else {
-        todo!("Host trap: invalid utf8 (target)");
-    };
+    let mut log_cmd = Reader::at(bytes, data);
+    let message_size: usize = log_cmd.u32()?.try_into().unwrap();
+    let message_data: usize = log_cmd.u32()?.try_into().unwrap();
+    let target_size: usize = log_cmd.u32()?.try_into().unwrap();
+    let target_data: usize = log_cmd.u32()?.try_into().unwrap();
 
-    log::trace!(target: "ardaku", "Log portal: target={target}");
+    trace!(
+        state.trace,
+        "Message (data, size) = ({target_data}, {target_size})"
+    );
+    let target = parse::slice(bytes, target_data, target_size)?;
+    let target = core::str::from_utf8(target)?;
 
-    log::trace!(target: "ardaku", "Message (data, size) = ({message_data}, {message_size})");
+    trace!(state.trace, "Log portal: target={target}");
 
-    let message = &bytes[message_data + 1..][..message_size];
-    let message = if let Ok(message) = core::str::from_utf8(message) {
-        message
-    } else {
-        todo!("Host trap: invalid utf8 (message)");
-    };
+    trace!(
+        state.trace,
+        "Message (data, size) = ({message_data}, {message_size})"
+    );
 
-    let level = match bytes[message_data] {
+    // The message starts with its level
+    let message =
+        parse::slice(bytes, message_data, message_size.saturating_add(1))?;
+    let (&level, message) = message.split_first().expect("not empty");
+    let message = core::str::from_utf8(message)?;
+
+    let level = match level {
         b'F' => {
             log::info!(target: "ardaku", "Panic triggered");
-            system.log(message, Level::Error, target);
-            todo!("Host trap: custom fatal");
+            state.system.log(message, Level::Error, target);
+            return Err(Error::GuestPanic {
+                target: target.into(),
+                message: message.into(),
+            });
         }
         b'E' => Level::Error,
         b'W' => Level::Warn,
         b'I' => Level::Info,
         b'D' => Level::Debug,
         b'T' => Level::Trace,
-        l => {
-            let ch = char::from(l);
-            todo!("Host trap: invalid log level: {ch}");
-        },
+        l => return Err(Error::InvalidLogLevel(l)),
     };
 
-    system.log(message, level, target);
+    state.system.log(message, level, target);
 
-    true
+    Ok(true)
 }
 
-impl<S: System> State<S> {
+impl<S: System + 'static> State<S> {
     fn bytes_and_state<'a>(
         caller: &'a mut Caller<'_, Self>,
     ) -> (&'a mut [u8], &'a mut State<S>) {
@@ -235,73 +903,113 @@ impl<S: System> State<S> {
         }
     }
 
-    /// Connect channels
-    fn connect(&mut self, bytes: &mut [u8], connect: Connect) {
-        type Callback<S> = fn(&mut S, u32, &mut [u8], u32, u32) -> bool;
+    /// Check the capability policy for connecting to a portal
+    fn capable(&mut self, portal: Portal) -> bool {
+        let capability = &mut self.capabilities[portal as usize];
 
+        if *capability == Capability::Ask {
+            *capability = if self.system.ask(portal) {
+                Capability::Allow
+            } else {
+                Capability::Deny
+            };
+        }
+
+        *capability == Capability::Allow
+    }
+
+    /// Connect a channel to a portal, returning the channel ID
+    fn connect_portal(&mut self, portal: Portal) -> u32 {
+        let callback: Callback<S> = match portal {
+            Portal::Log => log::<S>,
+            Portal::Prompt => prompt::<S>,
+            Portal::Account => portal::account::<S>,
+            Portal::User => portal::user::<S>,
+            Portal::System => portal::system::<S>,
+            Portal::Host => portal::host::<S>,
+            Portal::Hardware => portal::hardware::<S>,
+            Portal::Platform => portal::platform::<S>,
+            Portal::Spawn => portal::spawn::<S>,
+            Portal::SpawnBlocking => portal::spawn_blocking::<S>,
+            Portal::Channel => portal::channel::<S>,
+            Portal::Admin => portal::admin::<S>,
+            Portal::Timer => portal::timer::<S>,
+            Portal::Entropy => portal::entropy::<S>,
+            Portal::File => portal::file::<S>,
+            Portal::Store => portal::store::<S>,
+            Portal::Output => portal::output::<S>,
+            Portal::Args => portal::args::<S>,
+            Portal::Exit => portal::exit::<S>,
+        };
+        self.portals[portal as usize] = true;
+        let channel_id = self.channel();
+        self.conn_channels
+            .resize_with(usize::try_from(self.next_channel).unwrap(), || None);
+        self.conn_channels[usize::try_from(channel_id).unwrap()] =
+            Some(ConnectedChannel { portal, callback });
+
+        channel_id
+    }
+
+    /// Connect channels
+    fn connect(&mut self, bytes: &mut [u8], connect: Connect) -> EngineResult {
         let cap = connect.ready_capacity;
         let ptr = connect.ready_data;
-        log::trace!(target: "ardaku", "Connect: cap {cap}, ptr {ptr:x}");
+        trace!(self.trace, "Connect: cap {cap}, ptr {ptr:x}");
+
+        // The system writes to the ready list, so check it now
+        parse::slice(
+            bytes,
+            ptr.try_into().unwrap(),
+            usize::try_from(cap).unwrap().saturating_mul(4),
+        )?;
 
         self.ready_list = (connect.ready_capacity, connect.ready_data);
 
         let mut offset: usize = connect.portals_data.try_into().unwrap();
         for _ in 0..connect.portals_size {
-            let mut reader = Reader::new(&bytes[offset..]);
-            let portal = reader.u32();
-            let (portal, callback): (_, Callback<S>) = match portal {
-                0 => (Portal::Log, log::<S>),
-                1 => (Portal::Prompt, prompt::<S>),
-                2 => (Portal::Account, fixme::<S>),
-                3 => (Portal::User, fixme::<S>),
-                4 => (Portal::System, fixme::<S>),
-                5 => (Portal::Host, fixme::<S>),
-                6 => (Portal::Hardware, fixme::<S>),
-                7 => (Portal::Platform, fixme::<S>),
-                8 => (Portal::Spawn, fixme::<S>),
-                9 => (Portal::SpawnBlocking, fixme::<S>),
-                10 => (Portal::Channel, fixme::<S>),
-                11 => (Portal::Admin, fixme::<S>),
-                12.. => todo!("Host trap: invalid portal"),
+            let id = Reader::at(bytes, offset).u32()?;
+            let portal = Portal::from_id(id).ok_or(Error::InvalidPortal(id))?;
+            let channel_id = if self.capable(portal) {
+                self.connect_portal(portal)
+            } else {
+                log::warn!(target: "ardaku", "Denied portal: {portal:?}");
+                DENIED_CHANNEL
             };
-            self.portals[portal as u32 as usize] = true;
-            let channel_id = self.channel();
-            self.conn_channels.resize_with(
-                usize::try_from(self.next_channel).unwrap(),
-                || None,
-            );
-            self.conn_channels[usize::try_from(channel_id).unwrap()] =
-                Some(ConnectedChannel { portal, callback });
-
-            for (src, dst) in channel_id
-                .to_le_bytes()
-                .into_iter()
-                .zip(bytes[offset..].iter_mut())
-            {
-                *dst = src;
-            }
-            log::trace!(target: "ardaku", "Connect portal: {portal:?} (Ch{channel_id})");
+
+            Writer::at(bytes, offset).u32(channel_id)?;
+            trace!(self.trace, "Connect portal: {portal:?} (Ch{channel_id})");
 
             offset += core::mem::size_of::<u32>();
         }
+
+        Ok(())
     }
 
     /// Execute a command from an asynchronous request
-    fn execute(&mut self, bytes: &mut [u8], command: Command) -> bool {
+    fn execute(
+        &mut self,
+        bytes: &mut [u8],
+        command: Command,
+    ) -> EngineResult<bool> {
         if command.channel == 0 {
-            // FIXME: Trigger a trap if doesn't match
-            assert_eq!(command.size, 16);
+            if command.size != 16 {
+                return Err(Error::BadCommandSize {
+                    expected: &[16],
+                    found: command.size,
+                });
+            }
             let offset: usize = command.data.try_into().unwrap();
-            let mut reader = Reader::new(&bytes[offset..]);
+            let mut reader = Reader::at(bytes, offset);
             let connect = Connect {
-                portals_size: reader.u32(),
-                portals_data: reader.u32(),
-                ready_capacity: reader.u32(),
-                ready_data: reader.u32(),
+                portals_size: reader.u32()?,
+                portals_data: reader.u32()?,
+                ready_capacity: reader.u32()?,
+                ready_data: reader.u32()?,
             };
 
-            self.connect(bytes, connect);
-            true
+            self.connect(bytes, connect)?;
+            Ok(true)
         } else {
             let Command {
                 channel,
@@ -310,152 +1018,596 @@ impl<S: System> State<S> {
                 ready,
             } = command;
             let len = self.conn_channels.len();
-            log::trace!(target: "ardaku", "Ch{channel}: {len:?}");
-            let (portal, callback) = if let Some(ref cc) =
-                self.conn_channels[usize::try_from(channel).unwrap()]
+            trace!(self.trace, "Ch{channel}: {len:?}");
+            let (portal, callback) = if let Some(Some(cc)) =
+                self.conn_channels.get(usize::try_from(channel).unwrap())
             {
                 (cc.portal, cc.callback)
             } else {
-                todo!("Host trap: invalid channel");
+                return Err(Error::InvalidChannel(channel));
             };
 
             if !self.portals[portal as usize] {
-                todo!("Host trap: unsupported portal");
+                return Err(Error::InvalidChannel(channel));
             }
 
-            log::trace!(target: "ardaku", "Ch{channel}: {portal:?}");
+            trace!(self.trace, "Ch{channel}: {portal:?}");
 
-            callback(&mut self.system, ready, bytes, size, data)
+            callback(self, ready, bytes, size, data)
         }
     }
+
+    /// Execute a list of commands, and wait for them to become ready
+    fn ar(
+        &mut self,
+        bytes: &mut [u8],
+        size: u32,
+        data: u32,
+    ) -> EngineResult<u32> {
+        trace!(self.trace, "Syscall ({size} commands)");
+
+        let mut offset: usize = data.try_into().unwrap();
+        let mut none_waiting = true;
+        for _ in 0..size {
+            let mut reader = Reader::at(bytes, offset);
+            let command = Command {
+                size: reader.u32()?,
+                data: reader.u32()?,
+                channel: reader.u32()?,
+                ready: reader.u32()?,
+            };
+
+            trace!(self.trace, "DBG {command:?}");
+
+            none_waiting &= self.execute(bytes, command)?;
+            offset += 4 * core::mem::size_of::<u32>();
+        }
+
+        let ready_size = self.ready_list.0.try_into().unwrap();
+        let ready_data = self.ready_list.1.try_into().unwrap();
+
+        trace!(self.trace, "Ready ({none_waiting})");
+
+        self.blocking.poll(bytes, &mut self.ready_queue)?;
+        self.timers
+            .fire(self.system.monotonic(), &mut self.ready_queue);
+
+        let mut count = 0;
+
+        // Wait on commands from this request or earlier ones, unless the
+        // application is exiting
+        let waiting = self.exit_status.is_none()
+            && (!none_waiting
+                || self.timers.next().is_some()
+                || self.blocking.pending() != 0
+                || self.system_pending != 0);
+
+        if self.ready_queue.is_empty() && waiting {
+            let deadline = self.timers.next();
+
+            // Only block on the worker pool if the system has nothing to wake
+            // for, and only sleep if the system or a timer could wake the
+            // application up (not if it's only receiving on its own channels)
+            if self.system_pending == 0 && self.blocking.pending() != 0 {
+                let timeout = deadline.map(|deadline| {
+                    deadline.saturating_sub(self.system.monotonic())
+                });
+
+                self.blocking.wait(bytes, &mut self.ready_queue, timeout)?;
+            } else if self.system_pending != 0 || deadline.is_some() {
+                // Completed blocking tasks can't wake the system up, so check
+                // on them regularly while they run
+                let deadline = if self.blocking.pending() != 0 {
+                    let poll = self
+                        .system
+                        .monotonic()
+                        .saturating_add(portal::POLL_INTERVAL);
+
+                    Some(deadline.map_or(poll, |deadline| deadline.min(poll)))
+                } else {
+                    deadline
+                };
+
+                count =
+                    self.system.sleep(bytes, ready_size, ready_data, deadline);
+                self.system_pending = self.system_pending.saturating_sub(count);
+                self.blocking.poll(bytes, &mut self.ready_queue)?;
+            }
+
+            self.timers
+                .fire(self.system.monotonic(), &mut self.ready_queue);
+        }
+
+        if let Some(fuel) = &mut self.fuel {
+            *fuel += self.blocking.take_leftover();
+        }
+
+        let offset = ready_data + count * core::mem::size_of::<u32>();
+        let mut writer = Writer::at(bytes, offset);
+        while count < ready_size {
+            let Some(ready) = self.ready_queue.pop_front() else {
+                break;
+            };
+            writer.u32(ready)?;
+            count += 1;
+        }
+
+        if count == 0 {
+            let mut writer = Writer::at(bytes, ready_data);
+            for _ in 0..ready_size {
+                writer.u32(u32::MAX)?;
+            }
+        }
+
+        Ok(count.try_into().unwrap())
+    }
 }
 
-fn dbg<S>(mut caller: Caller<'_, State<S>>, size: u32, text: u32)
+fn dbg<S>(
+    mut caller: Caller<'_, State<S>>,
+    size: u32,
+    text: u32,
+) -> Result<(), Trap>
 where
     S: System + 'static,
 {
     let (bytes, _state) = State::bytes_and_state(&mut caller);
-    let string = core::str::from_utf8(
-        &bytes[usize::try_from(text).unwrap()..]
-            [..usize::try_from(size).unwrap()],
+    let string = parse::slice(
+        bytes,
+        usize::try_from(text).unwrap(),
+        usize::try_from(size).unwrap(),
     )
-    .expect("daku debug failure");
+    .map_err(Error::from)?;
+    let string = core::str::from_utf8(string).map_err(Error::from)?;
 
     log::trace!(target: "daku-dbg", "{string}");
+
+    Ok(())
 }
 
 /// Asynchronous Request
-fn ar<S>(mut caller: Caller<'_, State<S>>, size: u32, data: u32) -> u32
+fn ar<S>(
+    mut caller: Caller<'_, State<S>>,
+    size: u32,
+    data: u32,
+) -> Result<u32, Trap>
 where
     S: System + 'static,
 {
+    let fuel = caller.consume_fuel(0).ok();
+
+    caller.data_mut().fuel = fuel;
+
     let (bytes, state) = State::bytes_and_state(&mut caller);
+    let ready = state.ar(bytes, size, data);
+    let (fuel_left, exit_status) = (state.fuel, state.exit_status);
+
+    // Charge the application for the fuel its child tasks used, and return
+    // the fuel its blocking tasks didn't use
+    if let Some((fuel, fuel_left)) = fuel.zip(fuel_left) {
+        if fuel_left > fuel {
+            caller
+                .add_fuel(fuel_left - fuel)
+                .expect("fuel metering is enabled");
+        } else {
+            caller
+                .consume_fuel(fuel - fuel_left)
+                .map_err(|_| Trap::from(TrapCode::OutOfFuel))?;
+        }
+    }
+
+    // Host errors trap the guest, and are recovered by `run()`
+    let ready = ready.map_err(Trap::from)?;
+
+    // Exiting unwinds the guest, and is recovered by `run()`
+    if let Some(status) = exit_status {
+        return Err(Trap::i32_exit(status));
+    }
+
+    Ok(ready)
+}
+
+/// Run an Ardaku application with the default configuration.  `exe` must be
+/// a .wasm file.
+pub fn run<S>(system: S, exe: &[u8]) -> EngineResult<Report>
+where
+    S: System + 'static,
+{
+    Ardaku::default().run(system, exe)
+}
+
+/// Ardaku runtime configuration
+#[derive(Debug, Clone)]
+pub struct Ardaku {
+    fuel: Option<u64>,
+    max_pages: Option<u32>,
+    capabilities: [Capability; Portal::COUNT],
+    import_module: String,
+    entry: String,
+    memory_export: String,
+    trace: bool,
+    seed: Option<u64>,
+    namespace: String,
+    args: Vec<String>,
+    env: BTreeMap<String, String>,
+}
 
-    log::trace!(target: "ardaku", "Syscall ({size} commands)");
-
-    let mut offset: usize = data.try_into().unwrap();
-    let mut none_waiting = true;
-    for _ in 0..size {
-        let mut reader = Reader::new(&bytes[offset..]);
-        let command = Command {
-            size: reader.u32(),
-            data: reader.u32(),
-            channel: reader.u32(),
-            ready: reader.u32(),
+impl Default for Ardaku {
+    fn default() -> Self {
+        let mut capabilities = [Capability::Allow; Portal::COUNT];
+
+        capabilities[Portal::Account as usize] = Capability::Ask;
+        capabilities[Portal::System as usize] = Capability::Ask;
+        capabilities[Portal::Hardware as usize] = Capability::Deny;
+        capabilities[Portal::Admin as usize] = Capability::Deny;
+
+        Self {
+            fuel: None,
+            max_pages: None,
+            capabilities,
+            import_module: "daku".into(),
+            entry: "run".into(),
+            memory_export: "memory".into(),
+            trace: false,
+            seed: None,
+            namespace: "app".into(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+        }
+    }
+}
+
+impl Ardaku {
+    /// Start building a runtime configuration from the defaults
+    pub fn builder() -> Builder {
+        Builder(Self::default())
+    }
+
+    /// Run an Ardaku application.  `exe` must be a .wasm file.
+    ///
+    /// Returns [`Error::OutOfFuel`] if the application runs out of fuel, and
+    /// [`Error::OutOfMemory`] if the application's initial memory is larger
+    /// than the limit.  Growing memory past the limit fails within the
+    /// application.
+    pub fn run<S>(&self, system: S, exe: &[u8]) -> EngineResult<Report>
+    where
+        S: System + 'static,
+    {
+        let task = Task {
+            ardaku: Arc::new(self.clone()),
+            exe: exe.into(),
+            depth: 0,
+            pages: Arc::new(AtomicU32::new(0)),
         };
 
-        log::trace!(target: "ardaku", "DBG {command:?}");
+        task.run(
+            Rc::new(system),
+            Rc::default(),
+            &self.entry,
+            &mut self.fuel.clone(),
+        )
+    }
+}
+
+/// Application code and configuration, shared with child tasks
+#[derive(Clone)]
+struct Task {
+    ardaku: Arc<Ardaku>,
+    exe: Arc<[u8]>,
+    // Number of parent tasks
+    depth: u32,
+    // Pages allocated by the whole task tree
+    pages: Arc<AtomicU32>,
+}
 
-        none_waiting &= state.execute(bytes, command);
-        offset += 4 * core::mem::size_of::<u32>();
+impl Task {
+    /// Run the task's `entry` function to completion, sharing `tree` with the
+    /// rest of the task tree, with a `fuel` budget (`None` if unmetered) that's
+    /// replaced with the fuel left over
+    fn run<S>(
+        &self,
+        system: Rc<S>,
+        tree: Rc<portal::Tree>,
+        entry: &str,
+        fuel: &mut Option<u64>,
+    ) -> EngineResult<Report>
+    where
+        S: System + 'static,
+    {
+        self.call(system, tree, entry, (), fuel)
+            .map(|((), report)| report)
     }
 
-    let ready_size = state.ready_list.0.try_into().unwrap();
-    let ready_data = state.ready_list.1.try_into().unwrap();
+    /// Call the task's `entry` function with `params` to completion, sharing
+    /// `tree` with the rest of the task tree, with a `fuel` budget (`None` if
+    /// unmetered) that's replaced with the fuel left over
+    fn call<S, P, R>(
+        &self,
+        system: Rc<S>,
+        tree: Rc<portal::Tree>,
+        entry: &str,
+        params: P,
+        fuel: &mut Option<u64>,
+    ) -> EngineResult<(R, Report)>
+    where
+        S: System + 'static,
+        P: WasmParams,
+        R: WasmResults + Default,
+    {
+        let mut config = wasmi::Config::default();
+        config.consume_fuel(fuel.is_some());
+        let engine = wasmi::Engine::new(&config);
+        let module = Module::new(&engine, &self.exe[..])
+            .map_err(|_| Error::InvalidWasm)?;
+        let mut store = Store::new(
+            &engine,
+            State {
+                system,
+                task: self.clone(),
+                memory: MaybeUninit::uninit(),
+                limiter: Limiter::new(
+                    self.ardaku.max_pages,
+                    Arc::clone(&self.pages),
+                ),
+                ready_list: (0, 0),
+                ready_queue: VecDeque::new(),
+                capabilities: self.ardaku.capabilities,
+                trace: self.ardaku.trace,
+                portals: [false; Portal::COUNT],
+                drop_channels: Vec::new(),
+                next_channel: 1,
+                conn_channels: Vec::new(),
+                tree,
+                next_task: 0,
+                blocking: portal::Blocking::default(),
+                system_pending: 0,
+                timers: portal::Timers::default(),
+                seeded: self.ardaku.seed.map(portal::Seeded::new),
+                files: Vec::new(),
+                exit_status: None,
+                fuel: None,
+            },
+        );
+        store.limiter(|state| &mut state.limiter);
+        let budget = *fuel;
+
+        if let Some(budget) = budget {
+            store.add_fuel(budget).expect("fuel metering is enabled");
+        }
+        let async_request = Func::wrap(&mut store, ar);
+        let debug = Func::wrap(&mut store, dbg);
+        let mut linker = <Linker<State<S>>>::new(&engine);
+        linker
+            .define(&self.ardaku.import_module, "ar", async_request)
+            .map_err(|_| Error::LinkerFailed)?
+            .define(&self.ardaku.import_module, "dbg", debug)
+            .map_err(|_| Error::LinkerFailed)?;
+        let instance =
+            linker.instantiate(&mut store, &module).map_err(|_| {
+                if store.data().limiter.denied {
+                    Error::OutOfMemory
+                } else {
+                    Error::InvalidWasm
+                }
+            })?;
+        let instance = instance
+            .ensure_no_start(&mut store)
+            .map_err(|_| Error::InvalidWasm)?;
+        let memory = instance
+            .get_export(&mut store, &self.ardaku.memory_export)
+            .ok_or(Error::MissingMemory)?
+            .into_memory()
+            .ok_or(Error::MissingMemory)?;
+        store.data_mut().memory = MaybeUninit::new(memory);
+
+        let run = instance
+            .get_export(&store, entry)
+            .and_then(Extern::into_func)
+            .ok_or(Error::MissingRun)?
+            .typed::<P, R>(&mut store)
+            .map_err(|_| Error::MissingRun)?;
+
+        // And finally we can call the wasm!
+        let results = run.call(&mut store, params);
+
+        *fuel = store.consume_fuel(0).ok();
+
+        // Close files the application left open, even if it crashed, and drop
+        // its waiting receives
+        let state = store.data_mut();
+        state.tree.channels.borrow_mut().forget(self.depth);
+        for file in state.files.drain(..) {
+            state.system.close_file(file);
+        }
+
+        let exit_status = state.exit_status.unwrap_or(0);
+        let results = match results {
+            Err(trap) if trap.i32_exit_status().is_some() => R::default(),
+            results => results.map_err(Error::from)?,
+        };
+
+        //
+
+        let current_pages = unsafe {
+            store
+                .data_mut()
+                .memory
+                .assume_init()
+                .current_pages(&mut store)
+                .to_bytes()
+                .unwrap()
+                / 65_536
+        };
+
+        log::info!(target: "ardaku", "Pages allocated at exit: {current_pages}");
+        log::info!(target: "ardaku", " - As kB: {}", current_pages * 64);
 
-    log::trace!(target: "ardaku", "Ready ({none_waiting})");
+        let peak_pages = store.data().limiter.peak_pages;
 
-    if !none_waiting {
-        state
-            .system
-            .sleep(bytes, ready_size, ready_data)
-            .try_into()
-            .unwrap()
-    } else {
-        let ready_list = &mut bytes[ready_data..][..ready_size * 4];
-        let mut writer = Writer::new(ready_list);
-        for _ in 0..ready_size {
-            writer.u32(u32::MAX);
+        log::info!(target: "ardaku", "Peak pages allocated: {peak_pages}");
+
+        let report = Report {
+            fuel_consumed: budget
+                .zip(*fuel)
+                .map(|(budget, fuel)| budget.saturating_sub(fuel)),
+            peak_pages,
+            exit_status,
+        };
+
+        Ok((results, report))
+    }
+}
+
+/// Builder for [`Ardaku`] runtime configuration
+#[derive(Debug, Clone)]
+pub struct Builder(Ardaku);
+
+impl Builder {
+    /// Limit the number of instructions (wasmi fuel) the application, together
+    /// with its child tasks, may execute.  Unlimited by default.
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.0.fuel = Some(fuel);
+        self
+    }
+
+    /// Limit the number of 64 kB pages the application's memory, together with
+    /// the memory of its child tasks, may grow to.  Unlimited by default.
+    pub fn max_pages(mut self, pages: u32) -> Self {
+        self.0.max_pages = Some(pages);
+        self
+    }
+
+    /// Only allow the application to connect to `portals`, denying the rest.
+    /// By default, connecting to [`Portal::Account`] or [`Portal::System`]
+    /// asks the [`System`], connecting to [`Portal::Hardware`] or
+    /// [`Portal::Admin`] is denied, and all other portals are allowed.
+    pub fn portals(mut self, portals: &[Portal]) -> Self {
+        self.0.capabilities = [Capability::Deny; Portal::COUNT];
+        for portal in portals {
+            self.0.capabilities[*portal as usize] = Capability::Allow;
         }
+        self
+    }
+
+    /// Set the capability policy for connecting to `portal`.  By default,
+    /// connecting to [`Portal::Account`] or [`Portal::System`] asks the
+    /// [`System`], connecting to [`Portal::Hardware`] or [`Portal::Admin`] is
+    /// denied, and all other portals are allowed.
+    pub fn capability(
+        mut self,
+        portal: Portal,
+        capability: Capability,
+    ) -> Self {
+        self.0.capabilities[portal as usize] = capability;
+        self
+    }
+
+    /// Set the name of the module the application imports its syscalls from.
+    /// Default is `"daku"`.
+    pub fn import_module(mut self, name: impl Into<String>) -> Self {
+        self.0.import_module = name.into();
+        self
+    }
 
-        0
+    /// Set the name of the exported entry point function.  Default is
+    /// `"run"`.
+    pub fn entry(mut self, name: impl Into<String>) -> Self {
+        self.0.entry = name.into();
+        self
+    }
+
+    /// Set the name of the exported memory.  Default is `"memory"`.
+    pub fn memory_export(mut self, name: impl Into<String>) -> Self {
+        self.0.memory_export = name.into();
+        self
+    }
+
+    /// Log each command sent by the application, and how the portals handle
+    /// it, at the trace level.  Off by default.
+    pub fn trace(mut self, enabled: bool) -> Self {
+        self.0.trace = enabled;
+        self
+    }
+
+    /// Fill random bytes requested through [`Portal::Entropy`] from a
+    /// deterministic generator seeded with `seed`, instead of
+    /// [`System::random()`], so runs are reproducible.  The generator isn't
+    /// cryptographically secure.  Off by default.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.0.seed = Some(seed);
+        self
+    }
+
+    /// Set the namespace of the application's key-value storage, so
+    /// applications don't share keys.  Default is `"app"`.
+    pub fn namespace(mut self, name: impl Into<String>) -> Self {
+        self.0.namespace = name.into();
+        self
+    }
+
+    /// Set the command-line arguments passed to the application,
+    /// conventionally starting with its name.  None by default.
+    pub fn args<I, T>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.0.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set an environment variable passed to the application.  None by
+    /// default.
+    pub fn env(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.0.env.insert(name.into(), value.into());
+        self
+    }
+
+    /// Finish building the runtime configuration
+    pub fn build(self) -> Ardaku {
+        self.0
     }
 }
 
-/// Run an Ardaku application.  `exe` must be a .wasm file.
-pub fn run<S>(system: S, exe: &[u8]) -> EngineResult
-where
-    S: System + 'static,
-{
-    let engine = wasmi::Engine::default();
-    let module = Module::new(&engine, exe).map_err(|_| Error::InvalidWasm)?;
-    let mut store = Store::new(
-        &engine,
-        State {
-            system,
-            memory: MaybeUninit::uninit(),
-            ready_list: (0, 0),
-            portals: [false; Portal::Max as u32 as usize],
-            drop_channels: Vec::new(),
-            next_channel: 1,
-            conn_channels: Vec::new(),
-        },
-    );
-    let async_request = Func::wrap(&mut store, ar);
-    let debug = Func::wrap(&mut store, dbg);
-    let mut linker = <Linker<State<S>>>::new(&engine);
-    linker
-        .define("daku", "ar", async_request)
-        .map_err(|_| Error::LinkerFailed)?
-        .define("daku", "dbg", debug)
-        .map_err(|_| Error::LinkerFailed)?;
-    let instance = linker
-        .instantiate(&mut store, &module)
-        .map_err(|_| Error::InvalidWasm)?
-        .ensure_no_start(&mut store)
-        .map_err(|_| Error::InvalidWasm)?;
-    let memory = instance
-        .get_export(&mut store, "memory")
-        .ok_or(Error::MissingMemory)?
-        .into_memory()
-        .ok_or(Error::MissingMemory)?;
-    store.data_mut().memory = MaybeUninit::new(memory);
-
-    let run = instance
-        .get_export(&store, "run")
-        .and_then(Extern::into_func)
-        .ok_or(Error::MissingRun)?
-        .typed::<(), ()>(&mut store)
-        .map_err(|_| Error::MissingRun)?;
-
-    // And finally we can call the wasm!
-    run.call(&mut store, ()).map_err(Error::Crash)?;
-
-    //
-
-    let current_pages = unsafe {
-        store
-            .data_mut()
-            .memory
-            .assume_init()
-            .current_pages(&mut store)
-            .to_bytes()
-            .unwrap()
-            / 65_536
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    log::info!(target: "ardaku", "Pages allocated at exit: {current_pages}");
-    log::info!(target: "ardaku", " - As kB: {}", current_pages * 64);
+    const PAGE: usize = 65_536;
 
-    Ok(())
+    #[test]
+    fn limiter_peak_after_failed_growth() {
+        let tree_pages = Arc::new(AtomicU32::new(0));
+        let mut limiter = Limiter::new(None, Arc::clone(&tree_pages));
+
+        assert!(limiter.memory_growing(0, PAGE, None).unwrap());
+        assert!(limiter.memory_growing(PAGE, 101 * PAGE, None).unwrap());
+        limiter.memory_grow_failed(&MemoryError::OutOfBoundsGrowth);
+        assert_eq!(limiter.peak_pages, 1);
+        assert_eq!(tree_pages.load(Ordering::SeqCst), 1);
+
+        assert!(limiter.memory_growing(PAGE, 2 * PAGE, None).unwrap());
+        assert_eq!(limiter.peak_pages, 2);
+        drop(limiter);
+        assert_eq!(tree_pages.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn limiter_shared_by_task_tree() {
+        let tree_pages = Arc::new(AtomicU32::new(0));
+        let mut parent = Limiter::new(Some(3), Arc::clone(&tree_pages));
+        let mut child = Limiter::new(Some(3), Arc::clone(&tree_pages));
+
+        assert!(parent.memory_growing(0, 2 * PAGE, None).unwrap());
+        assert!(!child.memory_growing(0, 2 * PAGE, None).unwrap());
+        assert!(child.denied);
+        assert!(child.memory_growing(0, PAGE, None).unwrap());
+        drop(child);
+        assert!(parent.memory_growing(2 * PAGE, 3 * PAGE, None).unwrap());
+        assert_eq!(parent.peak_pages, 3);
+    }
 }
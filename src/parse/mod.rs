@@ -1,112 +1,231 @@
 //! Utilities to help parsing data between WASM and host
 
+use core::fmt;
+
+/// Parse Result
+pub type Result<T, E = OutOfBounds> = core::result::Result<T, E>;
+
+/// Out of bounds access of WASM memory
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OutOfBounds {
+    /// Address in WASM memory where the access started
+    pub addr: usize,
+    /// Length of the access (in bytes)
+    pub len: usize,
+}
+
+impl fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { addr, len } = self;
+
+        write!(f, "out of bounds access of {len} bytes at {addr:#x}")
+    }
+}
+
+/// Get the slice of WASM memory at `addr` that is `len` bytes long
+pub fn slice(bytes: &[u8], addr: usize, len: usize) -> Result<&[u8]> {
+    addr.checked_add(len)
+        .and_then(|end| bytes.get(addr..end))
+        .ok_or(OutOfBounds { addr, len })
+}
+
+/// Get the mutable slice of WASM memory at `addr` that is `len` bytes long
+pub fn slice_mut(
+    bytes: &mut [u8],
+    addr: usize,
+    len: usize,
+) -> Result<&mut [u8]> {
+    addr.checked_add(len)
+        .and_then(|end| bytes.get_mut(addr..end))
+        .ok_or(OutOfBounds { addr, len })
+}
+
 /// WASM memory writer
-pub struct Writer<'a>(&'a mut [u8]);
+pub struct Writer<'a> {
+    bytes: &'a mut [u8],
+    addr: usize,
+}
 
 impl<'a> Writer<'a> {
     /// Make new writer
     pub fn new(bytes: &'a mut [u8]) -> Self {
-        Self(bytes)
+        Self { bytes, addr: 0 }
     }
 
-    /// Send a u8 to the WASM module
-    pub fn u8(&mut self, byte: u8) {
-        self.0[..1].copy_from_slice(&byte.to_le_bytes());
+    /// Make new writer starting at `addr` within WASM memory
+    pub fn at(bytes: &'a mut [u8], addr: usize) -> Self {
+        let bytes = bytes.get_mut(addr..).unwrap_or_default();
 
-        // Hack around lifetime issues
-        let mut swap: &'a mut [u8] = &mut [];
-        core::mem::swap(&mut self.0, &mut swap);
-        let mut swap = swap.split_at_mut(1).1;
-        core::mem::swap(&mut self.0, &mut swap);
+        Self { bytes, addr }
     }
 
-    /// Send a u16 to the WASM module
-    pub fn u16(&mut self, half: u16) {
-        self.0[..2].copy_from_slice(&half.to_le_bytes());
+    /// Send a u8 to the WASM module
+    pub fn u8(&mut self, byte: u8) -> Result<()> {
+        self.take(1)?.copy_from_slice(&byte.to_le_bytes());
+        Ok(())
+    }
 
-        // Hack around lifetime issues
-        let mut swap: &'a mut [u8] = &mut [];
-        core::mem::swap(&mut self.0, &mut swap);
-        let mut swap = swap.split_at_mut(2).1;
-        core::mem::swap(&mut self.0, &mut swap);
+    /// Send a u16 to the WASM module
+    pub fn u16(&mut self, half: u16) -> Result<()> {
+        self.take(2)?.copy_from_slice(&half.to_le_bytes());
+        Ok(())
     }
 
     /// Send a u32 to the WASM module
-    pub fn u32(&mut self, word: u32) {
-        self.0[..4].copy_from_slice(&word.to_le_bytes());
-
-        // Hack around lifetime issues
-        let mut swap: &'a mut [u8] = &mut [];
-        core::mem::swap(&mut self.0, &mut swap);
-        let mut swap = swap.split_at_mut(4).1;
-        core::mem::swap(&mut self.0, &mut swap);
+    pub fn u32(&mut self, word: u32) -> Result<()> {
+        self.take(4)?.copy_from_slice(&word.to_le_bytes());
+        Ok(())
     }
 
     /// Send a u64 to the WASM module
-    pub fn u64(&mut self, long: u64) {
-        self.0[..8].copy_from_slice(&long.to_le_bytes());
-
-        // Hack around lifetime issues
-        let mut swap: &'a mut [u8] = &mut [];
-        core::mem::swap(&mut self.0, &mut swap);
-        let mut swap = swap.split_at_mut(8).1;
-        core::mem::swap(&mut self.0, &mut swap);
+    pub fn u64(&mut self, long: u64) -> Result<()> {
+        self.take(8)?.copy_from_slice(&long.to_le_bytes());
+        Ok(())
     }
 
     /// Send a UTF-8 string to the WASM module
-    pub fn str(&mut self, utf8: &str) {
-        let len = utf8.len();
-        self.0[..len].copy_from_slice(utf8.as_bytes());
+    pub fn str(&mut self, utf8: &str) -> Result<()> {
+        self.take(utf8.len())?.copy_from_slice(utf8.as_bytes());
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a mut [u8]> {
+        if len > self.bytes.len() {
+            return Err(OutOfBounds {
+                addr: self.addr,
+                len,
+            });
+        }
+
+        let (bytes, rest) = core::mem::take(&mut self.bytes).split_at_mut(len);
+
+        self.bytes = rest;
+        self.addr += len;
 
-        // Hack around lifetime issues
-        let mut swap: &'a mut [u8] = &mut [];
-        core::mem::swap(&mut self.0, &mut swap);
-        let mut swap = swap.split_at_mut(len).1;
-        core::mem::swap(&mut self.0, &mut swap);
+        Ok(bytes)
     }
 }
 
 /// WASM memory reader
-pub struct Reader<'a>(&'a [u8]);
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    addr: usize,
+}
 
 impl<'a> Reader<'a> {
     /// Make new reader
     pub fn new(bytes: &'a [u8]) -> Self {
-        Self(bytes)
+        Self { bytes, addr: 0 }
+    }
+
+    /// Make new reader starting at `addr` within WASM memory
+    pub fn at(bytes: &'a [u8], addr: usize) -> Self {
+        let bytes = bytes.get(addr..).unwrap_or_default();
+
+        Self { bytes, addr }
     }
 
     /// Receive a u8 from the WASM module
-    pub fn u8(&mut self) -> u8 {
-        let bytes = self.0[..1].try_into().expect("Out of bounds read");
-        self.0 = &self.0[1..];
-        u8::from_le_bytes(bytes)
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(u8::from_le_bytes(self.take(1)?.try_into().unwrap()))
     }
 
     /// Receive a u16 from the WASM module
-    pub fn u16(&mut self) -> u16 {
-        let bytes = self.0[..2].try_into().expect("Out of bounds read");
-        self.0 = &self.0[2..];
-        u16::from_le_bytes(bytes)
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
     }
 
     /// Receive a u32 from the WASM module
-    pub fn u32(&mut self) -> u32 {
-        let bytes = self.0[..4].try_into().expect("Out of bounds read");
-        self.0 = &self.0[4..];
-        u32::from_le_bytes(bytes)
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
     }
 
     /// Receive a u64 from the WASM module
-    pub fn u64(&mut self) -> u64 {
-        let bytes = self.0[..8].try_into().expect("Out of bounds read");
-        self.0 = &self.0[8..];
-        u64::from_le_bytes(bytes)
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
     }
 
     /// Receive a UTF-8 string from the WASM module
-    pub fn str(&mut self) -> Result<&str, core::str::Utf8Error> {
-        let bytes = self.0.get(0..).expect("Out of bounds read");
-        self.0 = &[];
+    pub fn str(&mut self) -> Result<&'a str, core::str::Utf8Error> {
+        let bytes = core::mem::take(&mut self.bytes);
+
+        self.addr += bytes.len();
         core::str::from_utf8(bytes)
     }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(OutOfBounds {
+                addr: self.addr,
+                len,
+            });
+        }
+
+        let (bytes, rest) = self.bytes.split_at(len);
+
+        self.bytes = rest;
+        self.addr += len;
+
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slice_past_end() {
+        let bytes = [0; 8];
+
+        assert_eq!(slice(&bytes, 4, 4), Ok(&bytes[4..]));
+        assert_eq!(slice(&bytes, 8, 0), Ok(&bytes[8..]));
+        assert_eq!(slice(&bytes, 6, 4), Err(OutOfBounds { addr: 6, len: 4 }));
+        assert_eq!(slice(&bytes, 9, 0), Err(OutOfBounds { addr: 9, len: 0 }));
+    }
+
+    #[test]
+    fn slice_overflow() {
+        let mut bytes = [0; 8];
+        let addr = usize::MAX - 1;
+        let error = OutOfBounds { addr, len: 4 };
+
+        assert_eq!(slice(&bytes, addr, 4), Err(error));
+        assert_eq!(slice_mut(&mut bytes, addr, 4), Err(error));
+    }
+
+    #[test]
+    fn reader_past_end() {
+        let bytes = [1, 0, 0, 0, 2, 0];
+        let mut reader = Reader::at(&bytes, 0);
+
+        assert_eq!(reader.u32(), Ok(1));
+        assert_eq!(reader.u32(), Err(OutOfBounds { addr: 4, len: 4 }));
+        assert_eq!(reader.u16(), Ok(2));
+        assert_eq!(
+            Reader::at(&bytes, 100).u32(),
+            Err(OutOfBounds { addr: 100, len: 4 }),
+        );
+        assert_eq!(
+            Reader::at(&bytes, usize::MAX).u8(),
+            Err(OutOfBounds {
+                addr: usize::MAX,
+                len: 1,
+            }),
+        );
+    }
+
+    #[test]
+    fn writer_past_end() {
+        let mut bytes = [0; 6];
+        let mut writer = Writer::at(&mut bytes, 2);
+
+        assert_eq!(writer.u32(1), Ok(()));
+        assert_eq!(writer.u8(2), Err(OutOfBounds { addr: 6, len: 1 }));
+        assert_eq!(
+            Writer::at(&mut bytes, 100).u32(3),
+            Err(OutOfBounds { addr: 100, len: 4 }),
+        );
+        assert_eq!(bytes, [0, 0, 1, 0, 0, 0]);
+    }
 }
@@ -0,0 +1,58 @@
+//! User account administration portal
+//!
+//! Commands start with a `u32` opcode:
+//!
+//!  - `0`: Create user (16 bytes) - `opcode`, username `size`, username `data`,
+//!    pointer to write the result to (`0` if successful, `1` if the system
+//!    refused)
+//!  - `1`: Delete user (16 bytes) - Same as create user
+//!  - `2`: List users (12 bytes) - `opcode`, pointer to the capacity of the
+//!    UTF-8 buffer, pointer to the buffer (`size: u32`, `reference: u32`) to
+//!    write the newline-separated usernames to
+//!
+//! All commands complete immediately, and are recorded in the system's logs
+//! with the `audit` target.  Applications need to be allowed to connect by the
+//! capability policy, which denies them by default.
+
+use alloc::format;
+
+use log::Level;
+
+use crate::{engine::Result, parse::Reader, portal, State, System};
+
+pub(crate) fn admin<S: System>(
+    state: &mut State<S>,
+    _ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    let system = &state.system;
+    let audit = |text: &str| system.log(text, Level::Info, "audit");
+
+    if Reader::at(bytes, data.try_into().unwrap()).u32()? == 2 {
+        return portal::read_text(bytes, size, data, state.trace, |field| {
+            (field == 2).then(|| {
+                audit("List users");
+                system.users().join("\n")
+            })
+        });
+    }
+
+    portal::write_text(bytes, size, data, |field, username| {
+        let (action, success) = match field {
+            // Usernames are listed one per line
+            0 if username.is_empty() || username.contains('\n') => {
+                ("Create", false)
+            }
+            0 => ("Create", system.create_user(username)),
+            1 => ("Delete", system.delete_user(username)),
+            _ => return None,
+        };
+        let result = if success { "succeeded" } else { "failed" };
+
+        audit(&format!("{action} user {username:?} {result}"));
+
+        Some(success)
+    })
+}
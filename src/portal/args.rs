@@ -0,0 +1,88 @@
+//! Arguments and environment portal
+//!
+//! Commands start with a `u32` opcode:
+//!
+//!  - `0`: Count (8 bytes) - `opcode`, pointer to write the number of arguments
+//!    and environment variables to (`args: u32`, `vars: u32`)
+//!  - `1`: Argument (20 bytes) - `opcode`, `index`, pointer to the capacity of
+//!    the UTF-8 buffer, pointer to the buffer (`size: u32`, `reference: u32`),
+//!    `status`
+//!  - `2`: Variable (24 bytes) - `opcode`, name `size`, name `data`, pointer to
+//!    the capacity of the UTF-8 buffer, pointer to the buffer, `status`
+//!  - `3`: Variable name (20 bytes) - Same as argument, for the name of the
+//!    environment variable at `index` (ordered by name)
+//!
+//! All commands complete immediately, negotiating buffer capacities the same
+//! way as the prompt portal.  The status is `0` if successful, or `1` if the
+//! argument or environment variable doesn't exist.
+
+use crate::{
+    engine::{Error, Result},
+    parse::{self, Reader, Writer},
+    portal, State, System,
+};
+
+pub(crate) fn args<S: System>(
+    state: &mut State<S>,
+    _ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let opcode = reader.u32()?;
+    let expected: &[u32] = match opcode {
+        0 => &[8],
+        1 | 3 => &[20],
+        2 => &[24],
+        _ => return Err(Error::InvalidCommand(opcode)),
+    };
+
+    if !expected.contains(&size) {
+        return Err(Error::BadCommandSize {
+            expected,
+            found: size,
+        });
+    }
+
+    let ardaku = &state.task.ardaku;
+
+    if opcode == 0 {
+        let mut writer = Writer::at(bytes, reader.u32()?.try_into().unwrap());
+
+        writer.u32(ardaku.args.len().try_into().unwrap_or(u32::MAX))?;
+        writer.u32(ardaku.env.len().try_into().unwrap_or(u32::MAX))?;
+
+        return Ok(true);
+    }
+
+    let text = match opcode {
+        1 | 3 => {
+            let index = usize::try_from(reader.u32()?).unwrap();
+
+            if opcode == 1 {
+                ardaku.args.get(index)
+            } else {
+                ardaku.env.keys().nth(index)
+            }
+        }
+        _ => {
+            let name_size = reader.u32()?.try_into().unwrap();
+            let name_data = reader.u32()?.try_into().unwrap();
+            let name = parse::slice(bytes, name_data, name_size)?;
+
+            ardaku.env.get(core::str::from_utf8(name)?)
+        }
+    };
+    let capacity = reader.u32()?.try_into().unwrap();
+    let buffer = reader.u32()?.try_into().unwrap();
+    let status = reader.u32()?.try_into().unwrap();
+
+    if let Some(text) = text {
+        portal::write_buffer(bytes, capacity, buffer, text.as_bytes())?;
+    }
+
+    Writer::at(bytes, status).u32(if text.is_some() { 0 } else { 1 })?;
+
+    Ok(true)
+}
@@ -0,0 +1,513 @@
+//! MPMC channel portal
+//!
+//! Commands start with a `u32` opcode, followed by the MPMC channel ID:
+//!
+//!  - `0`: Open (8 bytes) - `opcode`, pointer to write the new channel ID to
+//!  - `1`: Close (8 bytes) - `opcode`, `channel`
+//!  - `2`: Send (16 bytes) - `opcode`, `channel`, message `size`, message
+//!    `data`
+//!  - `3`: Receive (16 bytes) - `opcode`, `channel`, pointer to the capacity of
+//!    the buffer, pointer to the buffer (`size: u32`, `reference: u32`)
+//!
+//! Open, close and send complete immediately.  Receive completes through the
+//! ready list once a message is available, negotiating the buffer capacity
+//! the same way as the prompt portal (the message stays queued if the capacity
+//! is too small).  Closing a channel completes any waiting receives with an
+//! empty message.
+//!
+//! Channels are shared by the whole task tree, so an application can pass
+//! messages to and from the child tasks it spawns.  A receive only completes
+//! while the task waiting on it runs: messages sent by a child task are
+//! delivered to its parent's waiting receives once the child exits.  Receives
+//! still waiting when a task exits are dropped.
+//!
+//! Messages queued on the task tree's channels may take up as much host memory
+//! as the memory limit (or 16 MiB without one), and sending past that traps.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::mem;
+
+use crate::{
+    engine::{Error, Result},
+    parse::{self, Reader, Writer},
+    portal, State, System,
+};
+
+/// Limit on bytes of queued messages without a memory limit
+const MAX_QUEUED: usize = 16 * 1024 * 1024;
+
+/// MPMC channels created by the task tree
+#[derive(Default)]
+pub(crate) struct Channels {
+    channels: Vec<Option<Channel>>,
+    // Channel IDs that can be reclaimed
+    free: Vec<u32>,
+    // Bytes of host memory taken up by queued messages
+    queued: usize,
+    // Receives of other tasks on channels that have been closed
+    closed: Vec<Receiver>,
+}
+
+impl Channels {
+    /// Open a new channel
+    fn open(&mut self) -> u32 {
+        if let Some(id) = self.free.pop() {
+            self.channels[usize::try_from(id).unwrap()] =
+                Some(Channel::default());
+            id
+        } else {
+            let id = self.channels.len().try_into().unwrap();
+            self.channels.push(Some(Channel::default()));
+            id
+        }
+    }
+
+    /// Close a channel from the task at `depth`, completing its waiting
+    /// receives with an empty message
+    fn close(
+        &mut self,
+        id: u32,
+        depth: u32,
+        bytes: &mut [u8],
+        ready_queue: &mut VecDeque<u32>,
+    ) -> Result {
+        let channel = self
+            .channels
+            .get_mut(usize::try_from(id).unwrap())
+            .and_then(Option::take)
+            .ok_or(Error::InvalidChannel(id))?;
+
+        self.free.push(id);
+        self.queued -= channel
+            .messages
+            .iter()
+            .map(|message| queued(message))
+            .sum::<usize>();
+
+        for receiver in channel.receivers {
+            if receiver.depth == depth {
+                receiver.write(bytes, &[])?;
+                ready_queue.push_back(receiver.ready);
+            } else {
+                self.closed.push(receiver);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send a message on a channel from the task at `depth`, failing if more
+    /// than `limit` bytes of host memory would be taken up by queued messages
+    fn send(
+        &mut self,
+        id: u32,
+        depth: u32,
+        message: Vec<u8>,
+        limit: usize,
+        bytes: &mut [u8],
+        ready_queue: &mut VecDeque<u32>,
+    ) -> Result {
+        let size = queued(&message);
+        let channel = self.get(id)?;
+
+        channel.messages.push_back(message);
+
+        let freed = channel.deliver(depth, bytes, ready_queue)?;
+
+        self.queued = self.queued + size - freed;
+        if self.queued > limit {
+            return Err(Error::ChannelFull(id));
+        }
+
+        Ok(())
+    }
+
+    /// Receive a message on a channel, once one is available
+    fn receive(
+        &mut self,
+        id: u32,
+        receiver: Receiver,
+        bytes: &mut [u8],
+        ready_queue: &mut VecDeque<u32>,
+    ) -> Result {
+        let depth = receiver.depth;
+        let channel = self.get(id)?;
+
+        channel.receivers.push_back(receiver);
+        self.queued -= channel.deliver(depth, bytes, ready_queue)?;
+
+        Ok(())
+    }
+
+    /// Complete receives of the task at `depth` with messages sent, or
+    /// channels closed, by other tasks in the tree
+    pub(crate) fn deliver(
+        &mut self,
+        depth: u32,
+        bytes: &mut [u8],
+        ready_queue: &mut VecDeque<u32>,
+    ) -> Result {
+        for channel in self.channels.iter_mut().flatten() {
+            self.queued -= channel.deliver(depth, bytes, ready_queue)?;
+        }
+
+        let mut index = 0;
+        while let Some(receiver) = self.closed.get(index) {
+            if receiver.depth == depth {
+                let receiver = self.closed.remove(index);
+
+                receiver.write(bytes, &[])?;
+                ready_queue.push_back(receiver.ready);
+            } else {
+                index += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drop the receives of the task at `depth` once it has finished
+    pub(crate) fn forget(&mut self, depth: u32) {
+        for channel in self.channels.iter_mut().flatten() {
+            channel.receivers.retain(|receiver| receiver.depth != depth);
+        }
+        self.closed.retain(|receiver| receiver.depth != depth);
+    }
+
+    /// Get an open channel
+    fn get(&mut self, id: u32) -> Result<&mut Channel> {
+        self.channels
+            .get_mut(usize::try_from(id).unwrap())
+            .and_then(Option::as_mut)
+            .ok_or(Error::InvalidChannel(id))
+    }
+}
+
+#[derive(Default)]
+struct Channel {
+    messages: VecDeque<Vec<u8>>,
+    receivers: VecDeque<Receiver>,
+}
+
+impl Channel {
+    /// Deliver queued messages to receivers of the task at `depth`, returning
+    /// the bytes of host memory freed
+    fn deliver(
+        &mut self,
+        depth: u32,
+        bytes: &mut [u8],
+        ready_queue: &mut VecDeque<u32>,
+    ) -> Result<usize> {
+        let mut freed = 0;
+
+        while let Some(message) = self.messages.front() {
+            let Some(index) = self
+                .receivers
+                .iter()
+                .position(|receiver| receiver.depth == depth)
+            else {
+                break;
+            };
+            let receiver = self.receivers.remove(index).expect("in bounds");
+
+            if receiver.write(bytes, message)? {
+                freed += queued(message);
+                self.messages.pop_front();
+            }
+            ready_queue.push_back(receiver.ready);
+        }
+
+        Ok(freed)
+    }
+}
+
+/// Bytes of host memory a queued message takes up
+fn queued(message: &[u8]) -> usize {
+    message.len() + mem::size_of::<Vec<u8>>()
+}
+
+/// A receive waiting for a message
+struct Receiver {
+    // Depth of the receiving task in the task tree (child tasks run to
+    // completion within their parent, so only one task at each depth runs at a
+    // time)
+    depth: u32,
+    ready: u32,
+    capacity: usize,
+    buffer: usize,
+}
+
+impl Receiver {
+    /// Write a message to the receive buffer, returning `true` if it fit
+    fn write(&self, bytes: &mut [u8], message: &[u8]) -> Result<bool> {
+        portal::write_buffer(bytes, self.capacity, self.buffer, message)
+    }
+}
+
+pub(crate) fn channel<S: System>(
+    state: &mut State<S>,
+    ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let opcode = reader.u32()?;
+    let expected: &[u32] = match opcode {
+        0 | 1 => &[8],
+        2 | 3 => &[16],
+        _ => return Err(Error::InvalidCommand(opcode)),
+    };
+
+    if !expected.contains(&size) {
+        return Err(Error::BadCommandSize {
+            expected,
+            found: size,
+        });
+    }
+
+    let id = reader.u32()?;
+
+    let depth = state.task.depth;
+    let mut channels = state.tree.channels.borrow_mut();
+
+    match opcode {
+        0 => {
+            let channel = channels.open();
+
+            trace!(state.trace, "Open MPMC channel {channel}");
+            Writer::at(bytes, id.try_into().unwrap()).u32(channel)?;
+
+            Ok(true)
+        }
+        1 => {
+            trace!(state.trace, "Close MPMC channel {id}");
+            channels.close(id, depth, bytes, &mut state.ready_queue)?;
+
+            Ok(true)
+        }
+        2 => {
+            let message_size = reader.u32()?.try_into().unwrap();
+            let message_data = reader.u32()?.try_into().unwrap();
+            let message =
+                parse::slice(bytes, message_data, message_size)?.to_vec();
+            let limit = state.limiter.max_pages.map_or(MAX_QUEUED, |pages| {
+                usize::try_from(pages).unwrap().saturating_mul(65_536)
+            });
+
+            channels.send(
+                id,
+                depth,
+                message,
+                limit,
+                bytes,
+                &mut state.ready_queue,
+            )?;
+
+            Ok(true)
+        }
+        _ => {
+            let receiver = Receiver {
+                depth,
+                ready,
+                capacity: reader.u32()?.try_into().unwrap(),
+                buffer: reader.u32()?.try_into().unwrap(),
+            };
+
+            channels.receive(id, receiver, bytes, &mut state.ready_queue)?;
+
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Capacity pointer (at 0) and buffer (size at 4, reference at 8) for a
+    /// receive, with the buffer's data at 12
+    fn receiver(depth: u32, ready: u32, bytes: &mut [u8]) -> Receiver {
+        Writer::at(bytes, 0).u32(8).unwrap();
+        Writer::at(bytes, 8).u32(12).unwrap();
+
+        Receiver {
+            depth,
+            ready,
+            capacity: 0,
+            buffer: 4,
+        }
+    }
+
+    fn received(bytes: &[u8]) -> &[u8] {
+        let size = Reader::at(bytes, 4).u32().unwrap().try_into().unwrap();
+
+        &bytes[12..][..size]
+    }
+
+    #[test]
+    fn send_receive() {
+        let mut channels = Channels::default();
+        let mut bytes = [0; 20];
+        let mut ready_queue = VecDeque::new();
+        let id = channels.open();
+
+        channels
+            .send(id, 0, b"hello".to_vec(), 1024, &mut bytes, &mut ready_queue)
+            .unwrap();
+        assert!(ready_queue.is_empty());
+
+        let receiver = receiver(0, 7, &mut bytes);
+        channels
+            .receive(id, receiver, &mut bytes, &mut ready_queue)
+            .unwrap();
+        assert_eq!(ready_queue, [7]);
+        assert_eq!(received(&bytes), b"hello");
+        assert_eq!(channels.queued, 0);
+    }
+
+    #[test]
+    fn receive_too_small() {
+        let mut channels = Channels::default();
+        let mut bytes = [0; 20];
+        let mut ready_queue = VecDeque::new();
+        let id = channels.open();
+        let receiver = receiver(0, 7, &mut bytes);
+
+        channels
+            .receive(id, receiver, &mut bytes, &mut ready_queue)
+            .unwrap();
+        channels
+            .send(
+                id,
+                0,
+                b"too long!".to_vec(),
+                1024,
+                &mut bytes,
+                &mut ready_queue,
+            )
+            .unwrap();
+
+        // The required capacity is written, and the message stays queued
+        assert_eq!(ready_queue, [7]);
+        assert_eq!(Reader::at(&bytes, 0).u32().unwrap(), 9);
+        assert_eq!(channels.get(id).unwrap().messages.len(), 1);
+    }
+
+    #[test]
+    fn close() {
+        let mut channels = Channels::default();
+        let mut bytes = [0; 20];
+        let mut ready_queue = VecDeque::new();
+        let id = channels.open();
+        let receiver = receiver(0, 7, &mut bytes);
+
+        channels
+            .receive(id, receiver, &mut bytes, &mut ready_queue)
+            .unwrap();
+        channels.close(id, 0, &mut bytes, &mut ready_queue).unwrap();
+        assert_eq!(ready_queue, [7]);
+        assert_eq!(received(&bytes), b"");
+        assert!(matches!(
+            channels.close(id, 0, &mut bytes, &mut ready_queue),
+            Err(Error::InvalidChannel(_))
+        ));
+        assert!(matches!(
+            channels.send(
+                id,
+                0,
+                Vec::new(),
+                1024,
+                &mut bytes,
+                &mut ready_queue
+            ),
+            Err(Error::InvalidChannel(_))
+        ));
+
+        // Closed channel IDs are reused
+        assert_eq!(channels.open(), id);
+    }
+
+    #[test]
+    fn channel_full() {
+        let mut channels = Channels::default();
+        let mut bytes = [0; 20];
+        let mut ready_queue = VecDeque::new();
+        let id = channels.open();
+        let limit = 2 * queued(&[0; 100]);
+
+        for _ in 0..2 {
+            channels
+                .send(
+                    id,
+                    0,
+                    [0; 100].to_vec(),
+                    limit,
+                    &mut bytes,
+                    &mut ready_queue,
+                )
+                .unwrap();
+        }
+        assert!(matches!(
+            channels.send(
+                id,
+                0,
+                [0; 1].to_vec(),
+                limit,
+                &mut bytes,
+                &mut ready_queue
+            ),
+            Err(Error::ChannelFull(_))
+        ));
+
+        // Closing the channel frees its queued messages
+        channels.close(id, 0, &mut bytes, &mut ready_queue).unwrap();
+        assert_eq!(channels.queued, 0);
+    }
+
+    #[test]
+    fn between_tasks() {
+        let mut channels = Channels::default();
+        let mut parent = [0; 20];
+        let mut child = [0; 20];
+        let mut parent_ready = VecDeque::new();
+        let mut child_ready = VecDeque::new();
+        let id = channels.open();
+        let receiver = receiver(0, 7, &mut parent);
+
+        channels
+            .receive(id, receiver, &mut parent, &mut parent_ready)
+            .unwrap();
+
+        // The child's message waits for the parent to run again
+        channels
+            .send(id, 1, b"hi".to_vec(), 1024, &mut child, &mut child_ready)
+            .unwrap();
+        assert!(parent_ready.is_empty() && child_ready.is_empty());
+
+        channels.deliver(0, &mut parent, &mut parent_ready).unwrap();
+        assert_eq!(parent_ready, [7]);
+        assert_eq!(received(&parent), b"hi");
+    }
+
+    #[test]
+    fn forget() {
+        let mut channels = Channels::default();
+        let mut bytes = [0; 20];
+        let mut ready_queue = VecDeque::new();
+        let id = channels.open();
+        let receiver = receiver(1, 8, &mut bytes);
+
+        channels
+            .receive(id, receiver, &mut bytes, &mut ready_queue)
+            .unwrap();
+        channels.forget(1);
+
+        // The exited child's receive doesn't take the message
+        channels
+            .send(id, 0, b"hi".to_vec(), 1024, &mut bytes, &mut ready_queue)
+            .unwrap();
+        assert!(ready_queue.is_empty());
+        assert_eq!(channels.get(id).unwrap().messages.len(), 1);
+    }
+}
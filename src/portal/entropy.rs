@@ -0,0 +1,75 @@
+//! Entropy portal
+//!
+//! Entropy command (12 bytes):
+//!  - `size: u32`, `data: u32` - Buffer to fill with random bytes
+//!  - `status: u32` - Pointer to write the result to (`0` if successful, `1` if
+//!    no randomness is available)
+//!
+//! Completes immediately.  Random bytes come from the system, unless the
+//! embedder set a seed, in which case they come from a deterministic (and not
+//! cryptographically secure) generator.
+
+use crate::{
+    engine::{Error, Result},
+    parse::{self, Reader, Writer},
+    State, System,
+};
+
+/// Deterministic random number generator (SplitMix64)
+pub(crate) struct Seeded(u64);
+
+impl Seeded {
+    /// Create a generator from a seed
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// Fill `buffer` with the next random bytes
+    fn fill(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+            let mut z = self.0;
+
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^= z >> 31;
+            chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+pub(crate) fn entropy<S: System>(
+    state: &mut State<S>,
+    _ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    if size != 12 {
+        return Err(Error::BadCommandSize {
+            expected: &[12],
+            found: size,
+        });
+    }
+
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let buffer_size = reader.u32()?.try_into().unwrap();
+    let buffer_data = reader.u32()?.try_into().unwrap();
+    let status = reader.u32()?.try_into().unwrap();
+    let buffer = parse::slice_mut(bytes, buffer_data, buffer_size)?;
+    let success = if let Some(seeded) = &mut state.seeded {
+        seeded.fill(buffer);
+        true
+    } else {
+        state.system.random(buffer)
+    };
+
+    if !success {
+        log::warn!(target: "ardaku", "No randomness available");
+    }
+
+    Writer::at(bytes, status).u32(if success { 0 } else { 1 })?;
+
+    Ok(true)
+}
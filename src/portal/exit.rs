@@ -0,0 +1,36 @@
+//! Exit portal
+//!
+//! Exit command (4 bytes):
+//!  - `status: i32` - Exit status to report (`0` for success)
+//!
+//! Stops the application once the rest of the commands in the same request
+//! have been executed (without waiting for any of them to complete), returning
+//! the exit status from [`run()`](crate::run) in the [`Report`](crate::Report).
+
+use crate::{
+    engine::{Error, Result},
+    parse::Reader,
+    State, System,
+};
+
+pub(crate) fn exit<S: System>(
+    state: &mut State<S>,
+    _ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    if size != 4 {
+        return Err(Error::BadCommandSize {
+            expected: &[4],
+            found: size,
+        });
+    }
+
+    let status = Reader::at(bytes, data.try_into().unwrap()).u32()? as i32;
+
+    trace!(state.trace, "Exit with status {status}");
+    state.exit_status = Some(status);
+
+    Ok(true)
+}
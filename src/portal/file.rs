@@ -0,0 +1,217 @@
+//! Sandboxed filesystem portal
+//!
+//! Commands start with a `u32` opcode, and end with a pointer to write the
+//! result to (`0` if successful, `1` if it failed):
+//!
+//!  - `0`: Open (24 bytes) - `opcode`, `mode` (`0` read, `1` write, `2`
+//!    append), path `size`, path `data`, pointer to write the file handle to,
+//!    `status`
+//!  - `1`: Close (12 bytes) - `opcode`, `file`, `status`
+//!  - `2`: Read (24 bytes) - `opcode`, `file`, buffer `size`, buffer `data`,
+//!    pointer to write the number of bytes read to, `status`
+//!  - `3`: Write (24 bytes) - `opcode`, `file`, buffer `size`, buffer `data`,
+//!    pointer to write the number of bytes written to, `status`
+//!  - `4`: List (24 bytes) - `opcode`, directory path `size`, directory path
+//!    `data`, pointer to the capacity of the UTF-8 buffer, pointer to the
+//!    buffer (`size: u32`, `reference: u32`) to write the newline-separated
+//!    file names to, `status`
+//!  - `5`: Remove (16 bytes) - `opcode`, path `size`, path `data`, `status`
+//!
+//! Commands run synchronously when they're sent, then complete through the
+//! ready list of the same request.  Paths are `/`-separated and relative to
+//! the application's root directory, which is chosen by the system; `..`
+//! components fail instead of escaping the root.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    engine::{Error, Result},
+    parse::{self, Reader, Writer},
+    portal, OpenMode, State, System,
+};
+
+/// Confine a path to the application's root directory, returning `None` if it
+/// would escape
+fn sandbox(path: &str) -> Option<String> {
+    let mut components = Vec::new();
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            _ if component.contains(['\\', '\0']) => return None,
+            _ => components.push(component),
+        }
+    }
+
+    Some(components.join("/"))
+}
+
+/// Read a sandboxed path from application memory
+fn path(bytes: &[u8], reader: &mut Reader<'_>) -> Result<Option<String>> {
+    let size = reader.u32()?.try_into().unwrap();
+    let data = reader.u32()?.try_into().unwrap();
+    let path = core::str::from_utf8(parse::slice(bytes, data, size)?)?;
+    let sandboxed = sandbox(path);
+
+    if sandboxed.is_none() {
+        log::warn!(target: "ardaku", "Path escapes sandbox: {path:?}");
+    }
+
+    Ok(sandboxed)
+}
+
+pub(crate) fn file<S: System>(
+    state: &mut State<S>,
+    ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let opcode = reader.u32()?;
+    let expected: &[u32] = match opcode {
+        1 => &[12],
+        5 => &[16],
+        0 | 2 | 3 | 4 => &[24],
+        _ => return Err(Error::InvalidCommand(opcode)),
+    };
+
+    if !expected.contains(&size) {
+        return Err(Error::BadCommandSize {
+            expected,
+            found: size,
+        });
+    }
+
+    let system = &state.system;
+    let (status, success) = match opcode {
+        0 => {
+            let mode = reader.u32()?;
+            let mode =
+                OpenMode::from_id(mode).ok_or(Error::InvalidOpenMode(mode))?;
+            let path = path(bytes, &mut reader)?;
+            let handle = reader.u32()?.try_into().unwrap();
+            let status = reader.u32()?;
+            let file = path.and_then(|path| {
+                trace!(state.trace, "Open {mode:?} {path:?}");
+                system.open_file(&path, mode)
+            });
+
+            if let Some(file) = file {
+                state.files.push(file);
+                Writer::at(bytes, handle).u32(file)?;
+            }
+
+            (status, file.is_some())
+        }
+        1 => {
+            let file = reader.u32()?;
+            let status = reader.u32()?;
+            let index = state.files.iter().position(|open| *open == file);
+
+            if let Some(index) = index {
+                state.files.swap_remove(index);
+                system.close_file(file);
+            }
+
+            (status, index.is_some())
+        }
+        2 | 3 => {
+            let file = reader.u32()?;
+            let buffer_size = reader.u32()?.try_into().unwrap();
+            let buffer_data = reader.u32()?.try_into().unwrap();
+            let count = reader.u32()?.try_into().unwrap();
+            let status = reader.u32()?;
+            let buffer = parse::slice_mut(bytes, buffer_data, buffer_size)?;
+            let len = if !state.files.contains(&file) {
+                None
+            } else if opcode == 2 {
+                system.read_file(file, buffer)
+            } else {
+                system.write_file(file, buffer)
+            };
+
+            if let Some(len) = len {
+                let len = len.min(buffer_size).try_into().unwrap();
+
+                Writer::at(bytes, count).u32(len)?;
+            }
+
+            (status, len.is_some())
+        }
+        4 => {
+            let path = path(bytes, &mut reader)?;
+            let capacity = reader.u32()?.try_into().unwrap();
+            let buffer = reader.u32()?.try_into().unwrap();
+            let status = reader.u32()?;
+            let names = path.and_then(|path| system.list_files(&path));
+
+            if let Some(names) = &names {
+                let names = names.join("\n");
+
+                portal::write_buffer(
+                    bytes,
+                    capacity,
+                    buffer,
+                    names.as_bytes(),
+                )?;
+            }
+
+            (status, names.is_some())
+        }
+        _ => {
+            let path = path(bytes, &mut reader)?;
+            let status = reader.u32()?;
+            let removed = path.is_some_and(|path| {
+                trace!(state.trace, "Remove {path:?}");
+                system.remove_file(&path)
+            });
+
+            (status, removed)
+        }
+    };
+
+    if !success {
+        log::warn!(target: "ardaku", "File command {opcode} failed");
+    }
+
+    Writer::at(bytes, status.try_into().unwrap()).u32(if success {
+        0
+    } else {
+        1
+    })?;
+    state.ready_queue.push_back(ready);
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sandbox_parent() {
+        assert_eq!(sandbox(".."), None);
+        assert_eq!(sandbox("../a"), None);
+        assert_eq!(sandbox("a/../b"), None);
+        assert_eq!(sandbox("a/.."), None);
+    }
+
+    #[test]
+    fn sandbox_root() {
+        assert_eq!(sandbox("/a/b").as_deref(), Some("a/b"));
+        assert_eq!(sandbox("/../a"), None);
+        assert_eq!(sandbox("./a//b/").as_deref(), Some("a/b"));
+        assert_eq!(sandbox("").as_deref(), Some(""));
+        assert_eq!(sandbox("/").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn sandbox_separators() {
+        assert_eq!(sandbox("a\\..\\b"), None);
+        assert_eq!(sandbox("..\\a"), None);
+        assert_eq!(sandbox("a\0b"), None);
+        assert_eq!(sandbox("a/\0/b"), None);
+    }
+}
@@ -0,0 +1,87 @@
+//! Hardware control portal
+//!
+//! Commands start with a `u32` opcode, and write their result to a status
+//! pointer (`0` if successful, `1` if the system refused):
+//!
+//!  - `0`: Get clock (12 bytes) - `opcode`, pointer to write the hardware clock
+//!    time to (`u64` seconds since the Unix epoch), `status`
+//!  - `1`: Set clock (16 bytes) - `opcode`, `status`, hardware clock time
+//!    (`u64` seconds since the Unix epoch)
+//!  - `2`: Set profile (12 bytes) - `opcode`, performance profile (`0`
+//!    power-save, `1` balanced, `2` performance), `status`
+//!
+//! All commands complete immediately.  Applications need to be allowed to
+//! connect by the capability policy, which denies them by default.
+
+use crate::{
+    engine::{Error, Result},
+    parse::{Reader, Writer},
+    Profile, State, System,
+};
+
+pub(crate) fn hardware<S: System>(
+    state: &mut State<S>,
+    _ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let opcode = reader.u32()?;
+    let expected: &[u32] = match opcode {
+        0 | 2 => &[12],
+        1 => &[16],
+        _ => return Err(Error::InvalidCommand(opcode)),
+    };
+
+    if !expected.contains(&size) {
+        return Err(Error::BadCommandSize {
+            expected,
+            found: size,
+        });
+    }
+
+    let (status, success) = match opcode {
+        0 => {
+            let time = reader.u32()?.try_into().unwrap();
+            let status = reader.u32()?;
+            let clock = state.system.hardware_clock();
+
+            if let Some(clock) = clock {
+                Writer::at(bytes, time).u64(clock)?;
+            }
+
+            (status, clock.is_some())
+        }
+        1 => {
+            let status = reader.u32()?;
+            let clock = reader.u64()?;
+
+            log::info!(target: "ardaku", "Set hardware clock: {clock}");
+
+            (status, state.system.set_hardware_clock(clock))
+        }
+        _ => {
+            let profile = reader.u32()?;
+            let status = reader.u32()?;
+            let profile = Profile::from_id(profile)
+                .ok_or(Error::InvalidProfile(profile))?;
+
+            log::info!(target: "ardaku", "Set performance profile: {profile:?}");
+
+            (status, state.system.set_profile(profile))
+        }
+    };
+
+    if !success {
+        log::warn!(target: "ardaku", "Hardware command {opcode} failed");
+    }
+
+    Writer::at(bytes, status.try_into().unwrap()).u32(if success {
+        0
+    } else {
+        1
+    })?;
+
+    Ok(true)
+}
@@ -0,0 +1,186 @@
+//! Portal implementations
+
+mod account;
+mod admin;
+mod args;
+mod channel;
+mod entropy;
+mod exit;
+mod file;
+mod hardware;
+mod host;
+mod output;
+mod platform;
+mod spawn;
+mod spawn_blocking;
+mod store;
+mod system;
+mod timer;
+mod user;
+
+use alloc::string::String;
+#[cfg(feature = "std")]
+use core::cell::OnceCell;
+use core::cell::RefCell;
+
+pub use self::store::MemoryStore;
+pub(crate) use self::{
+    account::account,
+    admin::admin,
+    args::args,
+    channel::{channel, Channels},
+    entropy::{entropy, Seeded},
+    exit::exit,
+    file::file,
+    hardware::hardware,
+    host::host,
+    output::{output, write_output},
+    platform::{detect as detect_platform, platform},
+    spawn::spawn,
+    spawn_blocking::{spawn_blocking, Blocking, POLL_INTERVAL},
+    store::store,
+    system::system,
+    timer::{monotonic, timer, wall_clock, Timers},
+    user::user,
+};
+use crate::{
+    engine::{Error, Result},
+    parse::{self, Reader, Writer},
+};
+
+/// Host resources shared by the tasks of a task tree running on one thread
+#[derive(Default)]
+pub(crate) struct Tree {
+    // MPMC channels created by the task tree
+    pub(crate) channels: RefCell<Channels>,
+    // Worker threads running the task tree's blocking tasks, started once the
+    // first one is spawned
+    #[cfg(feature = "std")]
+    pool: OnceCell<spawn_blocking::Pool>,
+}
+
+/// Maximum number of nested child tasks
+const MAX_DEPTH: u32 = 8;
+
+/// Fuel for each child task of an application without a fuel limit
+const UNMETERED_FUEL: u64 = 1 << 34;
+
+/// Reserve fuel for a child task: half of its parent's remaining `fuel`, or
+/// [`UNMETERED_FUEL`] if the parent's fuel isn't limited
+fn reserve_fuel(fuel: &mut Option<u64>) -> u64 {
+    match fuel {
+        Some(fuel) => {
+            let reserved = *fuel / 2;
+
+            *fuel -= reserved;
+            reserved
+        }
+        None => UNMETERED_FUEL,
+    }
+}
+
+/// Write `data` to an application buffer, using the same capacity / size
+/// negotiation as [`System::read_line()`](crate::System::read_line).
+///
+///  - Capacity big enough: Overwrite buffer and new smaller size
+///  - Capacity too small: Overwrite required size, buffer untouched
+///
+/// # Parameters
+///  - `capacity`: Pointer to the capacity of the buffer (in bytes)
+///  - `buffer`: Pointer to the buffer (`size: u32`, `reference: u32`)
+///
+/// # Returns
+///  - `true` if the buffer was overwritten
+pub(crate) fn write_buffer(
+    bytes: &mut [u8],
+    capacity: usize,
+    buffer: usize,
+    data: &[u8],
+) -> Result<bool> {
+    let size: u32 = data.len().try_into().unwrap_or(u32::MAX);
+
+    if Reader::at(bytes, capacity).u32()? < size {
+        Writer::at(bytes, capacity).u32(size)?;
+        return Ok(false);
+    }
+
+    let mut reader = Reader::at(bytes, buffer);
+    let _size = reader.u32()?;
+    let reference = usize::try_from(reader.u32()?).unwrap();
+
+    parse::slice_mut(bytes, reference, data.len())?.copy_from_slice(data);
+    Writer::at(bytes, buffer).u32(size)?;
+
+    Ok(true)
+}
+
+/// Handle a command reading one of a portal's text fields (12 bytes):
+///  - `field: u32` - Which field to read, looked up with `text`
+///  - `capacity: u32` - Pointer to the capacity of the UTF-8 buffer (in bytes)
+///  - `buffer: u32` - Pointer to the UTF-8 buffer (`size: u32`, `reference:
+///    u32`)
+///
+/// Completes immediately.
+pub(crate) fn read_text(
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+    trace: bool,
+    text: impl FnOnce(u32) -> Option<String>,
+) -> Result<bool> {
+    if size != 12 {
+        return Err(Error::BadCommandSize {
+            expected: &[12],
+            found: size,
+        });
+    }
+
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let field = reader.u32()?;
+    let capacity = reader.u32()?.try_into().unwrap();
+    let buffer = reader.u32()?.try_into().unwrap();
+    let text = text(field).ok_or(Error::InvalidCommand(field))?;
+
+    trace!(trace, "Read field {field}: {text}");
+    write_buffer(bytes, capacity, buffer, text.as_bytes())?;
+
+    Ok(true)
+}
+
+/// Handle a command setting one of a portal's text fields (16 bytes):
+///  - `field: u32` - Which field to set, set with `set`
+///  - `size: u32`, `data: u32` - UTF-8 value to set the field to
+///  - `status: u32` - Pointer to write the result to (`0` if successful, `1` if
+///    the system refused)
+///
+/// Completes immediately.
+pub(crate) fn write_text(
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+    set: impl FnOnce(u32, &str) -> Option<bool>,
+) -> Result<bool> {
+    if size != 16 {
+        return Err(Error::BadCommandSize {
+            expected: &[16],
+            found: size,
+        });
+    }
+
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let field = reader.u32()?;
+    let value_size = reader.u32()?.try_into().unwrap();
+    let value_data = reader.u32()?.try_into().unwrap();
+    let status = reader.u32()?.try_into().unwrap();
+    let value = parse::slice(bytes, value_data, value_size)?;
+    let value = core::str::from_utf8(value)?;
+    let success = set(field, value).ok_or(Error::InvalidCommand(field))?;
+
+    if !success {
+        log::warn!(target: "ardaku", "Failed to set field {field}");
+    }
+
+    Writer::at(bytes, status).u32(if success { 0 } else { 1 })?;
+
+    Ok(true)
+}
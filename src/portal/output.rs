@@ -0,0 +1,66 @@
+//! Raw output stream portal
+//!
+//! Output command (12 bytes):
+//!  - `stream: u32` - `0` for standard output, `1` for standard error
+//!  - `size: u32`, `data: u32` - Bytes to write to the stream
+//!
+//! Completes immediately.  Unlike the log portal, the bytes are written as-is,
+//! without a level, target or trailing newline.
+
+use crate::{
+    engine::{Error, Result},
+    parse::{self, Reader},
+    State, Stream, System,
+};
+
+pub(crate) fn output<S: System>(
+    state: &mut State<S>,
+    _ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    if size != 12 {
+        return Err(Error::BadCommandSize {
+            expected: &[12],
+            found: size,
+        });
+    }
+
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let stream = reader.u32()?;
+    let stream = Stream::from_id(stream).ok_or(Error::InvalidStream(stream))?;
+    let output_size = reader.u32()?.try_into().unwrap();
+    let output_data = reader.u32()?.try_into().unwrap();
+
+    state
+        .system
+        .write_output(stream, parse::slice(bytes, output_data, output_size)?);
+
+    Ok(true)
+}
+
+/// Write to the process's standard output or error
+#[cfg(feature = "std")]
+pub(crate) fn write_output(stream: Stream, data: &[u8]) {
+    use std::io::{self, Write};
+
+    let result = match stream {
+        Stream::Stdout => {
+            let mut stdout = io::stdout().lock();
+
+            stdout.write_all(data).and_then(|()| stdout.flush())
+        }
+        Stream::Stderr => io::stderr().lock().write_all(data),
+    };
+
+    if let Err(error) = result {
+        log::warn!(target: "ardaku", "Failed to write {stream:?}: {error}");
+    }
+}
+
+/// Output streams aren't available without `std`
+#[cfg(not(feature = "std"))]
+pub(crate) fn write_output(stream: Stream, data: &[u8]) {
+    let _ = (stream, data);
+}
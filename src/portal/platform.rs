@@ -0,0 +1,105 @@
+//! Hardware information portal
+//!
+//! Commands start with a `u32` opcode:
+//!
+//!  - `0`: Specs (8 bytes) - `opcode`, pointer to write the specs to (`cores:
+//!    u32`, `gpu: u32` (`1` if present), `memory: u64` (in bytes))
+//!  - `1`: CPU model (12 bytes) - `opcode`, pointer to the capacity of the
+//!    UTF-8 buffer, pointer to the buffer (`size: u32`, `reference: u32`)
+//!  - `2`: Architecture (12 bytes) - Same as the CPU model
+//!
+//! All commands complete immediately, negotiating buffer capacities the same
+//! way as the prompt portal.
+
+use crate::{
+    engine::{Error, Result},
+    parse::{Reader, Writer},
+    portal, Platform, State, System,
+};
+
+pub(crate) fn platform<S: System>(
+    state: &mut State<S>,
+    _ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let opcode = reader.u32()?;
+    let platform = state.system.platform();
+
+    if opcode != 0 {
+        return portal::read_text(bytes, size, data, state.trace, |field| {
+            match field {
+                1 => Some(platform.cpu_model),
+                2 => Some(platform.arch),
+                _ => None,
+            }
+        });
+    }
+
+    if size != 8 {
+        return Err(Error::BadCommandSize {
+            expected: &[8],
+            found: size,
+        });
+    }
+
+    let mut writer = Writer::at(bytes, reader.u32()?.try_into().unwrap());
+
+    writer.u32(platform.cores)?;
+    writer.u32(platform.gpu.into())?;
+    writer.u64(platform.memory)?;
+
+    Ok(true)
+}
+
+/// Detect the platform from `/proc` (and `/sys`) on Linux
+#[cfg(feature = "std")]
+pub(crate) fn detect() -> Platform {
+    use std::fs;
+
+    let mut platform = Platform::default();
+
+    if let Ok(cpuinfo) = fs::read_to_string("/proc/cpuinfo") {
+        platform.cores = cpuinfo
+            .lines()
+            .filter(|line| line.starts_with("processor"))
+            .count()
+            .try_into()
+            .unwrap_or(u32::MAX);
+
+        if let Some((_, model)) = cpuinfo
+            .lines()
+            .find(|line| line.starts_with("model name"))
+            .and_then(|line| line.split_once(':'))
+        {
+            platform.cpu_model = model.trim().into();
+        }
+    }
+
+    if let Ok(meminfo) = fs::read_to_string("/proc/meminfo") {
+        platform.memory = meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix("MemTotal:"))
+            .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse().ok())
+            .map_or(0, |kb: u64| kb.saturating_mul(1024));
+    }
+
+    platform.arch = std::env::consts::ARCH.into();
+    platform.gpu = fs::read_dir("/sys/class/drm").is_ok_and(|mut entries| {
+        entries.any(|entry| {
+            entry.is_ok_and(|entry| {
+                entry.file_name().to_string_lossy().starts_with("card")
+            })
+        })
+    });
+
+    platform
+}
+
+/// Platform detection isn't available without `std`
+#[cfg(not(feature = "std"))]
+pub(crate) fn detect() -> Platform {
+    Platform::default()
+}
@@ -0,0 +1,122 @@
+//! Task spawning portal
+//!
+//! Spawn command (24 bytes):
+//!  - `module_size: u32`, `module_data: u32` - WebAssembly module to spawn, or
+//!    size 0 to spawn another instance of the application itself
+//!  - `entry_size: u32`, `entry_data: u32` - UTF-8 name of the exported entry
+//!    point function
+//!  - `handle: u32` - Pointer to write the child task's handle to, which
+//!    identifies it in the logs
+//!  - `status: u32` - Pointer to write how the child task ended to: `failed:
+//!    u32` (`1` if it failed, `0` if it ran to completion), followed by its
+//!    `exit_status: i32` (`0` if it failed or didn't exit through the exit
+//!    portal)
+//!
+//! The child task shares the system with its parent, and runs to completion
+//! before its ready ID is added to the ready list.  Failures of the child task
+//! are logged and reported through `failed`, and don't affect the parent.
+//!
+//! The child task reserves half of its parent's remaining fuel, and the fuel
+//! it doesn't use is returned once it exits.  If the parent's fuel isn't
+//! limited, the child may still only execute
+//! [`UNMETERED_FUEL`](super::UNMETERED_FUEL) instructions, so it can't hang its
+//! parent.  Its memory counts towards its parent's memory limit, and it shares
+//! its parent's MPMC channels.
+//!
+//! Since the child runs within its parent's request, it may not connect to the
+//! prompt or timer portals, which would have it sleep on (and take over) the
+//! parent's pending system events.
+
+use alloc::{rc::Rc, string::String, sync::Arc};
+
+use crate::{
+    engine::{Error, Result},
+    parse::{self, Reader, Writer},
+    portal::{self, MAX_DEPTH},
+    Ardaku, Capability, Portal, State, System, Task,
+};
+
+pub(crate) fn spawn<S: System + 'static>(
+    state: &mut State<S>,
+    ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    if size != 24 {
+        return Err(Error::BadCommandSize {
+            expected: &[24],
+            found: size,
+        });
+    }
+
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let module_size = reader.u32()?.try_into().unwrap();
+    let module_data = reader.u32()?.try_into().unwrap();
+    let entry_size = reader.u32()?.try_into().unwrap();
+    let entry_data = reader.u32()?.try_into().unwrap();
+    let handle = reader.u32()?.try_into().unwrap();
+    let status = reader.u32()?.try_into().unwrap();
+    let entry = parse::slice(bytes, entry_data, entry_size)?;
+    let entry = String::from(core::str::from_utf8(entry)?);
+    let exe = if module_size == 0 {
+        state.task.exe.clone()
+    } else {
+        parse::slice(bytes, module_data, module_size)?.into()
+    };
+    let mut ardaku = Ardaku::clone(&state.task.ardaku);
+
+    ardaku.capabilities[Portal::Prompt as usize] = Capability::Deny;
+    ardaku.capabilities[Portal::Timer as usize] = Capability::Deny;
+
+    let task = Task {
+        ardaku: Arc::new(ardaku),
+        exe,
+        depth: state.task.depth + 1,
+        pages: Arc::clone(&state.task.pages),
+    };
+    let child = state.next_task;
+
+    state.next_task = state.next_task.wrapping_add(1);
+    Writer::at(bytes, handle).u32(child)?;
+    trace!(state.trace, "Spawn task {child}: {entry}");
+
+    let (failed, exit_status) = if task.depth > MAX_DEPTH {
+        log::warn!(target: "ardaku", "Task {child} nested too deep");
+        (1, 0)
+    } else {
+        let mut fuel = Some(portal::reserve_fuel(&mut state.fuel));
+        let result = task.run(
+            Rc::clone(&state.system),
+            Rc::clone(&state.tree),
+            &entry,
+            &mut fuel,
+        );
+
+        // Return the fuel the child didn't use
+        if let Some(remaining) = &mut state.fuel {
+            *remaining += fuel.unwrap_or(0);
+        }
+
+        match result {
+            Ok(report) => (0, report.exit_status),
+            Err(error) => {
+                log::warn!(target: "ardaku", "Task {child} failed: {error}");
+                (1, 0)
+            }
+        }
+    };
+    let mut writer = Writer::at(bytes, status);
+
+    writer.u32(failed)?;
+    writer.u32(exit_status as u32)?;
+    state.ready_queue.push_back(ready);
+    // Complete the parent's receives with messages sent by the child
+    state.tree.channels.borrow_mut().deliver(
+        state.task.depth,
+        bytes,
+        &mut state.ready_queue,
+    )?;
+
+    Ok(false)
+}
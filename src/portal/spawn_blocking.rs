@@ -0,0 +1,425 @@
+//! Blocking task spawning portal
+//!
+//! Spawn blocking command (32 bytes):
+//!  - `module_size: u32`, `module_data: u32` - WebAssembly module to spawn, or
+//!    size 0 to spawn another instance of the application itself
+//!  - `entry_size: u32`, `entry_data: u32` - UTF-8 name of the exported entry
+//!    point function, which must have the signature `(u32) -> u32`
+//!  - `argument: u32` - Argument to call the entry point function with
+//!  - `handle: u32` - Pointer to write the child task's handle to, which
+//!    identifies it in the logs
+//!  - `result: u32` - Pointer to write the entry point's return value to
+//!  - `status: u32` - Pointer to write the child task's exit status to (`0` if
+//!    successful, `1` if it failed)
+//!
+//! Blocking tasks are for compute-heavy work.  They don't share the system
+//! with their parent, and may only connect to the log portal (which logs
+//! through the `log` crate), so blocking host I/O and message passing aren't
+//! supported.  With the `std` feature they run on a pool of host worker
+//! threads shared by the task tree, and their ready ID is added to the ready
+//! list once they complete, so the parent keeps running in the meantime (once
+//! the application exits, queued blocking tasks are skipped, and running ones
+//! are waited for).  Without it, they run to completion before the command
+//! returns.
+//!
+//! Each blocking task reserves half of its parent's remaining fuel, and the
+//! fuel it doesn't use is returned once it completes.  If the parent's fuel
+//! isn't limited, the task may still only execute
+//! [`UNMETERED_FUEL`](super::UNMETERED_FUEL) instructions, so it can't keep a
+//! worker thread busy forever.  Its memory counts towards its parent's memory
+//! limit.
+
+use alloc::{collections::VecDeque, rc::Rc, string::String, sync::Arc};
+#[cfg(feature = "std")]
+use core::{
+    num::NonZeroUsize,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+#[cfg(feature = "std")]
+use std::{
+    sync::{mpsc, Mutex},
+    thread::{self, JoinHandle},
+    vec::Vec,
+};
+
+use log::Level;
+
+use crate::{
+    engine::{Error, Result},
+    parse::{self, Reader, Writer},
+    portal::{self, Tree, MAX_DEPTH},
+    Ardaku, Capability, Portal, State, System, Task,
+};
+
+/// How long the system may sleep before checking for completed blocking tasks
+/// (in nanoseconds)
+pub(crate) const POLL_INTERVAL: u64 = 10_000_000;
+
+/// Results of a completed blocking task: where to write them, the return
+/// value (`None` if the task failed), and the fuel left over
+type Done = (Output, Option<u32>, u64);
+
+/// Blocking tasks spawned by the application
+#[derive(Default)]
+pub(crate) struct Blocking {
+    // Number of blocking tasks that haven't completed yet
+    pending: usize,
+    // Fuel left over by completed blocking tasks, not yet returned
+    leftover: u64,
+    // Channel the worker pool sends the results of completed blocking tasks
+    // on, once one is spawned
+    #[cfg(feature = "std")]
+    done: Option<(mpsc::Sender<Done>, mpsc::Receiver<Done>)>,
+}
+
+impl Blocking {
+    /// Number of blocking tasks that haven't completed yet
+    pub(crate) fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Take the fuel left over by completed blocking tasks
+    pub(crate) fn take_leftover(&mut self) -> u64 {
+        core::mem::take(&mut self.leftover)
+    }
+
+    /// Write the results of a completed blocking task
+    fn finish(
+        &mut self,
+        bytes: &mut [u8],
+        ready_queue: &mut VecDeque<u32>,
+        (output, result, leftover): Done,
+    ) -> Result {
+        self.leftover += leftover;
+        output.write(bytes, ready_queue, result)
+    }
+
+    /// Start running a blocking task on the task tree's worker pool
+    #[cfg(feature = "std")]
+    fn spawn(
+        &mut self,
+        tree: &Tree,
+        job: Job,
+        _bytes: &mut [u8],
+        _ready_queue: &mut VecDeque<u32>,
+    ) -> Result {
+        let (done, _) = self.done.get_or_insert_with(mpsc::channel);
+
+        tree.pool
+            .get_or_init(Pool::new)
+            .jobs
+            .as_ref()
+            .expect("worker pool is running")
+            .send((job, done.clone()))
+            .expect("worker pool is running");
+        self.pending += 1;
+
+        Ok(())
+    }
+
+    /// Start running a blocking task
+    #[cfg(not(feature = "std"))]
+    fn spawn(
+        &mut self,
+        _tree: &Tree,
+        job: Job,
+        bytes: &mut [u8],
+        ready_queue: &mut VecDeque<u32>,
+    ) -> Result {
+        self.finish(bytes, ready_queue, job.run())
+    }
+
+    /// Write the results of completed blocking tasks, adding their ready IDs
+    /// to `ready_queue`
+    #[cfg(feature = "std")]
+    pub(crate) fn poll(
+        &mut self,
+        bytes: &mut [u8],
+        ready_queue: &mut VecDeque<u32>,
+    ) -> Result {
+        while let Some(done) = self
+            .done
+            .as_ref()
+            .and_then(|(_, done)| done.try_recv().ok())
+        {
+            self.pending -= 1;
+            self.finish(bytes, ready_queue, done)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the results of completed blocking tasks, adding their ready IDs
+    /// to `ready_queue`
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn poll(
+        &mut self,
+        _bytes: &mut [u8],
+        _ready_queue: &mut VecDeque<u32>,
+    ) -> Result {
+        Ok(())
+    }
+
+    /// Block until a pending task completes, or `timeout` nanoseconds pass,
+    /// then [`poll()`](Self::poll)
+    #[cfg(feature = "std")]
+    pub(crate) fn wait(
+        &mut self,
+        bytes: &mut [u8],
+        ready_queue: &mut VecDeque<u32>,
+        timeout: Option<u64>,
+    ) -> Result {
+        let Some((_, done)) = &self.done else {
+            return Ok(());
+        };
+        let done = match timeout {
+            Some(timeout) => {
+                done.recv_timeout(Duration::from_nanos(timeout)).ok()
+            }
+            // This task holds a sender, so this can't fail
+            None => done.recv().ok(),
+        };
+
+        if let Some(done) = done {
+            self.pending -= 1;
+            self.finish(bytes, ready_queue, done)?;
+        }
+
+        self.poll(bytes, ready_queue)
+    }
+
+    /// Block until a pending task completes, or `timeout` nanoseconds pass,
+    /// then [`poll()`](Self::poll)
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn wait(
+        &mut self,
+        bytes: &mut [u8],
+        ready_queue: &mut VecDeque<u32>,
+        _timeout: Option<u64>,
+    ) -> Result {
+        self.poll(bytes, ready_queue)
+    }
+}
+
+/// Host worker threads running the blocking tasks of a task tree, each
+/// sending its results to the task that spawned it
+#[cfg(feature = "std")]
+pub(super) struct Pool {
+    jobs: Option<mpsc::Sender<(Job, mpsc::Sender<Done>)>>,
+    workers: Vec<JoinHandle<()>>,
+    // Set once the pool is dropped, so queued jobs are skipped
+    stopped: Arc<AtomicBool>,
+}
+
+#[cfg(feature = "std")]
+impl Pool {
+    /// Start one worker thread for each available CPU
+    fn new() -> Self {
+        let (jobs, receiver) = mpsc::channel::<(Job, mpsc::Sender<Done>)>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let workers =
+            thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let workers = (0..workers)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let stopped = Arc::clone(&stopped);
+
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    let Ok((job, done)) = job else {
+                        break;
+                    };
+
+                    // The task that spawned the job may have exited already
+                    if !stopped.load(Ordering::SeqCst) {
+                        let _ = done.send(job.run());
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            jobs: Some(jobs),
+            workers,
+            stopped,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Pool {
+    /// Skip queued jobs, and wait for the workers to finish the jobs they're
+    /// running (which are limited by their fuel), so none outlive the task
+    /// tree
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.jobs = None;
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Where to write the results of a blocking task
+#[derive(Copy, Clone)]
+struct Output {
+    ready: u32,
+    result: usize,
+    status: usize,
+}
+
+impl Output {
+    /// Write the return value (`None` if the task failed) and exit status
+    fn write(
+        self,
+        bytes: &mut [u8],
+        ready_queue: &mut VecDeque<u32>,
+        result: Option<u32>,
+    ) -> Result {
+        let status = if result.is_some() { 0 } else { 1 };
+
+        Writer::at(bytes, self.result).u32(result.unwrap_or(0))?;
+        Writer::at(bytes, self.status).u32(status)?;
+        ready_queue.push_back(self.ready);
+
+        Ok(())
+    }
+}
+
+/// A blocking task waiting to run
+struct Job {
+    task: Task,
+    handle: u32,
+    entry: String,
+    argument: u32,
+    output: Output,
+    // Fuel reserved for the task
+    fuel: u64,
+}
+
+impl Job {
+    /// Run the task to completion, returning its return value if successful
+    fn run(self) -> Done {
+        let Self {
+            task,
+            handle,
+            entry,
+            argument,
+            output,
+            fuel,
+        } = self;
+
+        if task.depth > MAX_DEPTH {
+            log::warn!(target: "ardaku", "Task {handle} nested too deep");
+            return (output, None, fuel);
+        }
+
+        let mut fuel = Some(fuel);
+        let result = match task.call(
+            Rc::new(Detached),
+            Rc::default(),
+            &entry,
+            argument,
+            &mut fuel,
+        ) {
+            Ok((result, _report)) => Some(result),
+            Err(error) => {
+                log::warn!(target: "ardaku", "Task {handle} failed: {error}");
+                None
+            }
+        };
+
+        (output, result, fuel.unwrap_or(0))
+    }
+}
+
+/// System for blocking tasks, which only supports logging
+struct Detached;
+
+impl System for Detached {
+    fn sleep(
+        &self,
+        _bytes: &mut [u8],
+        _size: usize,
+        _data: usize,
+        _deadline: Option<u64>,
+    ) -> usize {
+        0
+    }
+
+    fn log(&self, text: &str, level: Level, target: &str) {
+        log::log!(target: target, level, "{text}")
+    }
+
+    fn read_line(&self, _ready: u32, _data: usize, _size: usize) {}
+}
+
+pub(crate) fn spawn_blocking<S: System + 'static>(
+    state: &mut State<S>,
+    ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    if size != 32 {
+        return Err(Error::BadCommandSize {
+            expected: &[32],
+            found: size,
+        });
+    }
+
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let module_size = reader.u32()?.try_into().unwrap();
+    let module_data = reader.u32()?.try_into().unwrap();
+    let entry_size = reader.u32()?.try_into().unwrap();
+    let entry_data = reader.u32()?.try_into().unwrap();
+    let argument = reader.u32()?;
+    let handle = reader.u32()?.try_into().unwrap();
+    let output = Output {
+        ready,
+        result: reader.u32()?.try_into().unwrap(),
+        status: reader.u32()?.try_into().unwrap(),
+    };
+    let entry = parse::slice(bytes, entry_data, entry_size)?;
+    let entry = String::from(core::str::from_utf8(entry)?);
+    let exe = if module_size == 0 {
+        state.task.exe.clone()
+    } else {
+        parse::slice(bytes, module_data, module_size)?.into()
+    };
+    let mut ardaku = Ardaku::clone(&state.task.ardaku);
+
+    ardaku.capabilities = [Capability::Deny; Portal::COUNT];
+    ardaku.capabilities[Portal::Log as usize] = Capability::Allow;
+
+    let task = Task {
+        ardaku: Arc::new(ardaku),
+        exe,
+        depth: state.task.depth + 1,
+        pages: Arc::clone(&state.task.pages),
+    };
+    let child = state.next_task;
+
+    state.next_task = state.next_task.wrapping_add(1);
+    Writer::at(bytes, handle).u32(child)?;
+    trace!(state.trace, "Spawn blocking task {child}: {entry}");
+
+    let fuel = portal::reserve_fuel(&mut state.fuel);
+    let job = Job {
+        task,
+        handle: child,
+        entry,
+        argument,
+        output,
+        fuel,
+    };
+
+    state
+        .blocking
+        .spawn(&state.tree, job, bytes, &mut state.ready_queue)?;
+
+    Ok(false)
+}
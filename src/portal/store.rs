@@ -0,0 +1,166 @@
+//! Key-value storage portal
+//!
+//! Commands start with a `u32` opcode, and end with a pointer to write the
+//! result to (`0` if successful, `1` if it failed):
+//!
+//!  - `0`: Get (24 bytes) - `opcode`, key `size`, key `data`, pointer to the
+//!    capacity of the buffer, pointer to the buffer (`size: u32`, `reference:
+//!    u32`) to write the value to, `status`
+//!  - `1`: Put (24 bytes) - `opcode`, key `size`, key `data`, value `size`,
+//!    value `data`, `status`
+//!  - `2`: Delete (16 bytes) - `opcode`, key `size`, key `data`, `status`
+//!  - `3`: List (24 bytes) - `opcode`, prefix `size`, prefix `data`, pointer to
+//!    the capacity of the UTF-8 buffer, pointer to the buffer (`size: u32`,
+//!    `reference: u32`) to write the newline-separated keys to, `status`
+//!
+//! All commands complete immediately, negotiating buffer capacities the same
+//! way as the prompt portal.  Keys are UTF-8 without newlines, and are
+//! namespaced per application by the embedder.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::cell::RefCell;
+
+use crate::{
+    engine::{Error, Result},
+    parse::{self, Reader, Writer},
+    portal, State, System,
+};
+
+/// In-memory key-value storage, for systems without persistent storage to
+/// implement the key-value [`System`] methods with
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    namespaces: RefCell<BTreeMap<String, BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryStore {
+    /// Create empty storage
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the value of a key
+    pub fn get(&self, namespace: &str, key: &str) -> Option<Vec<u8>> {
+        self.namespaces.borrow().get(namespace)?.get(key).cloned()
+    }
+
+    /// Set the value of a key
+    pub fn put(&self, namespace: &str, key: &str, value: &[u8]) {
+        self.namespaces
+            .borrow_mut()
+            .entry(namespace.to_string())
+            .or_default()
+            .insert(key.to_string(), value.to_vec());
+    }
+
+    /// Delete a key, returning `true` if it existed
+    pub fn delete(&self, namespace: &str, key: &str) -> bool {
+        self.namespaces
+            .borrow_mut()
+            .get_mut(namespace)
+            .is_some_and(|keys| keys.remove(key).is_some())
+    }
+
+    /// List the keys starting with `prefix`
+    pub fn list(&self, namespace: &str, prefix: &str) -> Vec<String> {
+        self.namespaces
+            .borrow()
+            .get(namespace)
+            .map(|keys| {
+                keys.range(prefix.to_string()..)
+                    .map(|(key, _)| key)
+                    .take_while(|key| key.starts_with(prefix))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Read a UTF-8 key from application memory
+fn key<'a>(bytes: &'a [u8], reader: &mut Reader<'_>) -> Result<&'a str> {
+    let size = reader.u32()?.try_into().unwrap();
+    let data = reader.u32()?.try_into().unwrap();
+
+    Ok(core::str::from_utf8(parse::slice(bytes, data, size)?)?)
+}
+
+pub(crate) fn store<S: System>(
+    state: &mut State<S>,
+    _ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let opcode = reader.u32()?;
+    let expected: &[u32] = match opcode {
+        2 => &[16],
+        0 | 1 | 3 => &[24],
+        _ => return Err(Error::InvalidCommand(opcode)),
+    };
+
+    if !expected.contains(&size) {
+        return Err(Error::BadCommandSize {
+            expected,
+            found: size,
+        });
+    }
+
+    let system = &state.system;
+    let namespace = &state.task.ardaku.namespace;
+    let key = String::from(key(bytes, &mut reader)?);
+    let (status, success) = match opcode {
+        0 | 3 => {
+            let capacity = reader.u32()?.try_into().unwrap();
+            let buffer = reader.u32()?.try_into().unwrap();
+            let status = reader.u32()?;
+            let value = if opcode == 0 {
+                system.get_value(namespace, &key)
+            } else {
+                system
+                    .list_keys(namespace, &key)
+                    .map(|keys| keys.join("\n").into_bytes())
+            };
+
+            if let Some(value) = &value {
+                portal::write_buffer(bytes, capacity, buffer, value)?;
+            }
+
+            (status, value.is_some())
+        }
+        1 => {
+            let value_size = reader.u32()?.try_into().unwrap();
+            let value_data = reader.u32()?.try_into().unwrap();
+            let status = reader.u32()?;
+            let value = parse::slice(bytes, value_data, value_size)?;
+
+            // Keys are listed one per line
+            let success =
+                !key.contains('\n') && system.put_value(namespace, &key, value);
+
+            (status, success)
+        }
+        _ => {
+            let status = reader.u32()?;
+
+            (status, system.delete_value(namespace, &key))
+        }
+    };
+
+    if !success {
+        log::debug!(target: "ardaku", "Key-value command {opcode} failed");
+    }
+
+    Writer::at(bytes, status.try_into().unwrap()).u32(if success {
+        0
+    } else {
+        1
+    })?;
+
+    Ok(true)
+}
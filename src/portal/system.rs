@@ -0,0 +1,26 @@
+//! System settings portal
+//!
+//! System command (16 bytes):
+//!  - `field: u32` - `0` to set the system nickname, `1` to set the hostname
+//!  - `size: u32`, `data: u32` - UTF-8 value to set the field to
+//!  - `status: u32` - Pointer to write the result to (`0` if successful, `1` if
+//!    the system refused)
+//!
+//! Completes immediately.  Applications need to be allowed to connect by the
+//! capability policy, which asks the system by default.
+
+use crate::{engine::Result, portal, State, System};
+
+pub(crate) fn system<S: System>(
+    state: &mut State<S>,
+    _ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    portal::write_text(bytes, size, data, |field, value| match field {
+        0 => Some(state.system.set_nickname(value)),
+        1 => Some(state.system.set_hostname(value)),
+        _ => None,
+    })
+}
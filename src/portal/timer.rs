@@ -0,0 +1,136 @@
+//! Timer portal
+//!
+//! Commands start with a `u32` opcode:
+//!
+//!  - `0`: Monotonic time (8 bytes) - `opcode`, pointer to write the time to
+//!    (`u64` nanoseconds since an arbitrary point)
+//!  - `1`: Wall-clock time (8 bytes) - `opcode`, pointer to write the time to
+//!    (`u64` nanoseconds since the Unix epoch)
+//!  - `2`: Timer (16 bytes) - `opcode`, `absolute: u32` (`1` if `time` is a
+//!    monotonic deadline, `0` if it's a duration), `time: u64` (nanoseconds)
+//!
+//! Reading the time completes immediately.  Timers complete through the ready
+//! list once the monotonic time reaches their deadline.
+
+use alloc::collections::{BinaryHeap, VecDeque};
+use core::cmp::Reverse;
+
+use crate::{
+    engine::{Error, Result},
+    parse::{Reader, Writer},
+    State, System,
+};
+
+/// Pending timers, ordered by deadline
+#[derive(Default)]
+pub(crate) struct Timers {
+    timers: BinaryHeap<Reverse<(u64, u32)>>,
+}
+
+impl Timers {
+    /// Add the ready IDs of timers with a deadline at or before `now` to
+    /// `ready_queue`
+    pub(crate) fn fire(&mut self, now: u64, ready_queue: &mut VecDeque<u32>) {
+        while let Some(&Reverse((deadline, ready))) = self.timers.peek() {
+            if deadline > now {
+                break;
+            }
+
+            self.timers.pop();
+            ready_queue.push_back(ready);
+        }
+    }
+
+    /// Get the earliest pending deadline
+    pub(crate) fn next(&self) -> Option<u64> {
+        self.timers.peek().map(|&Reverse((deadline, _))| deadline)
+    }
+}
+
+pub(crate) fn timer<S: System>(
+    state: &mut State<S>,
+    ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    let mut reader = Reader::at(bytes, data.try_into().unwrap());
+    let opcode = reader.u32()?;
+    let expected: &[u32] = match opcode {
+        0 | 1 => &[8],
+        2 => &[16],
+        _ => return Err(Error::InvalidCommand(opcode)),
+    };
+
+    if !expected.contains(&size) {
+        return Err(Error::BadCommandSize {
+            expected,
+            found: size,
+        });
+    }
+
+    match opcode {
+        0 | 1 => {
+            let time = if opcode == 0 {
+                state.system.monotonic()
+            } else {
+                state.system.wall_clock()
+            };
+
+            Writer::at(bytes, reader.u32()?.try_into().unwrap()).u64(time)?;
+
+            Ok(true)
+        }
+        _ => {
+            let absolute = reader.u32()? != 0;
+            let time = reader.u64()?;
+            let deadline = if absolute {
+                time
+            } else {
+                state.system.monotonic().saturating_add(time)
+            };
+
+            trace!(state.trace, "Timer {ready} at {deadline}");
+            state.timers.timers.push(Reverse((deadline, ready)));
+
+            Ok(false)
+        }
+    }
+}
+
+/// Nanoseconds since the first call
+#[cfg(feature = "std")]
+pub(crate) fn monotonic() -> u64 {
+    use std::{sync::OnceLock, time::Instant};
+
+    static START: OnceLock<Instant> = OnceLock::new();
+
+    START
+        .get_or_init(Instant::now)
+        .elapsed()
+        .as_nanos()
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+/// Time isn't available without `std`
+#[cfg(not(feature = "std"))]
+pub(crate) fn monotonic() -> u64 {
+    0
+}
+
+/// Nanoseconds since the Unix epoch
+#[cfg(feature = "std")]
+pub(crate) fn wall_clock() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos().try_into().unwrap_or(u64::MAX))
+}
+
+/// Time isn't available without `std`
+#[cfg(not(feature = "std"))]
+pub(crate) fn wall_clock() -> u64 {
+    0
+}
@@ -0,0 +1,26 @@
+//! User information portal
+//!
+//! User command (12 bytes):
+//!  - `field: u32` - `0` for the username, `1` for the display name
+//!  - `capacity: u32` - Pointer to the capacity of the UTF-8 buffer (in bytes)
+//!  - `buffer: u32` - Pointer to the UTF-8 buffer (`size: u32`, `reference:
+//!    u32`)
+//!
+//! Completes immediately, negotiating the buffer capacity the same way as the
+//! prompt portal.
+
+use crate::{engine::Result, portal, State, System};
+
+pub(crate) fn user<S: System>(
+    state: &mut State<S>,
+    _ready: u32,
+    bytes: &mut [u8],
+    size: u32,
+    data: u32,
+) -> Result<bool> {
+    portal::read_text(bytes, size, data, state.trace, |field| match field {
+        0 => Some(state.system.username()),
+        1 => Some(state.system.display_name()),
+        _ => None,
+    })
+}
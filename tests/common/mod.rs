@@ -0,0 +1,59 @@
+//! System shared by the integration tests
+
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use ardaku::System;
+use log::Level;
+
+/// System recording the messages logged by the application
+pub struct Logs {
+    start: Instant,
+    messages: Rc<RefCell<Vec<String>>>,
+}
+
+impl Logs {
+    /// Create a system, and the list it records log messages to
+    pub fn new() -> (Self, Rc<RefCell<Vec<String>>>) {
+        let messages = Rc::default();
+        let system = Self {
+            start: Instant::now(),
+            messages: Rc::clone(&messages),
+        };
+
+        (system, messages)
+    }
+}
+
+impl System for Logs {
+    fn sleep(
+        &self,
+        _bytes: &mut [u8],
+        _size: usize,
+        _data: usize,
+        deadline: Option<u64>,
+    ) -> usize {
+        let deadline = deadline.expect("nothing to wake up for");
+
+        thread::sleep(Duration::from_nanos(
+            deadline.saturating_sub(self.monotonic()),
+        ));
+        0
+    }
+
+    fn log(&self, text: &str, _level: Level, _target: &str) {
+        self.messages.borrow_mut().push(text.into());
+    }
+
+    fn read_line(&self, _ready: u32, _data: usize, _size: usize) {
+        unreachable!()
+    }
+
+    fn monotonic(&self) -> u64 {
+        self.start.elapsed().as_nanos().try_into().unwrap()
+    }
+}
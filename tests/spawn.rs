@@ -0,0 +1,122 @@
+mod common;
+
+use ardaku::{Ardaku, Builder};
+
+use self::common::Logs;
+
+/// Connects to the log (channel 1), spawn (channel 2) and exit (channel 3)
+/// portals
+const GUEST: &str = r#"
+(module
+  (import "daku" "ar" (func $ar (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  ;; Connect
+  (data (i32.const 0) "\10\00\00\00\10\00\00\00\00\00\00\00\00\00\00\00")
+  (data (i32.const 16) "\03\00\00\00\40\00\00\00\04\00\00\00\80\00\00\00")
+  (data (i32.const 64) "\00\00\00\00\08\00\00\00\12\00\00\00")
+  ;; Log, spawn and exit commands
+  (data (i32.const 32) "\10\00\00\00\30\00\00\00\01\00\00\00\00\00\00\00")
+  (data (i32.const 1000) "\18\00\00\00\2c\01\00\00\02\00\00\00\05\00\00\00")
+  (data (i32.const 1016) "\04\00\00\00\f0\01\00\00\03\00\00\00\00\00\00\00")
+  (data (i32.const 496) "\03\00\00\00")
+  ;; Log messages, starting with their level
+  (data (i32.const 200) "Itask")
+  (data (i32.const 210) "Ichild")
+  (data (i32.const 220) "Idone")
+  (data (i32.const 230) "Inest")
+  (data (i32.const 240) "Ideep")
+  ;; Entry points
+  (data (i32.const 500) "child")
+  (data (i32.const 510) "bad")
+  (data (i32.const 520) "nest")
+  (data (i32.const 530) "spin")
+  (data (i32.const 540) "exit")
+
+  (func $connect
+    (drop (call $ar (i32.const 1) (i32.const 0))))
+
+  (func $log (param $data i32) (param $size i32)
+    (i32.store (i32.const 48) (local.get $size))
+    (i32.store (i32.const 52) (local.get $data))
+    (drop (call $ar (i32.const 1) (i32.const 32))))
+
+  ;; Spawn another instance of the application, returning whether it failed
+  ;; (with its exit status at 408)
+  (func $spawn (param $data i32) (param $size i32) (result i32)
+    (i32.store (i32.const 308) (local.get $size))
+    (i32.store (i32.const 312) (local.get $data))
+    (i32.store (i32.const 316) (i32.const 400))
+    (i32.store (i32.const 320) (i32.const 404))
+    (if (i32.ne (call $ar (i32.const 1) (i32.const 1000)) (i32.const 1))
+      (then unreachable))
+    (if (i32.ne (i32.load (i32.const 128)) (i32.const 5))
+      (then unreachable))
+    (i32.load (i32.const 404)))
+
+  (func (export "run")
+    (call $connect)
+    (call $log (i32.const 200) (i32.const 4))
+    (if (i32.ne (call $spawn (i32.const 500) (i32.const 5)) (i32.const 0))
+      (then unreachable))
+    (if (i32.ne (call $spawn (i32.const 510) (i32.const 3)) (i32.const 1))
+      (then unreachable))
+    (if (i32.ne (call $spawn (i32.const 540) (i32.const 4)) (i32.const 0))
+      (then unreachable))
+    (if (i32.ne (i32.load (i32.const 408)) (i32.const 3))
+      (then unreachable))
+    (call $log (i32.const 220) (i32.const 4)))
+
+  (func (export "child")
+    (call $connect)
+    (call $log (i32.const 210) (i32.const 5)))
+
+  (func (export "bad")
+    unreachable)
+
+  (func (export "exit")
+    (call $connect)
+    (drop (call $ar (i32.const 1) (i32.const 1016))))
+
+  (func (export "nest")
+    (call $connect)
+    (call $log (i32.const 230) (i32.const 4))
+    (if (i32.ne (call $spawn (i32.const 520) (i32.const 4)) (i32.const 0))
+      (then (call $log (i32.const 240) (i32.const 4)))))
+
+  (func (export "spin")
+    (loop $spin (br $spin)))
+
+  (func (export "fuel")
+    (call $connect)
+    (if (i32.ne (call $spawn (i32.const 530) (i32.const 4)) (i32.const 1))
+      (then unreachable))
+    (call $log (i32.const 220) (i32.const 4)))
+)
+"#;
+
+fn run(builder: Builder, entry: &str) -> Vec<String> {
+    let exe = wat::parse_str(GUEST).unwrap();
+    let (system, messages) = Logs::new();
+
+    builder.entry(entry).build().run(system, &exe).unwrap();
+    messages.take()
+}
+
+#[test]
+fn spawn() {
+    assert_eq!(run(Ardaku::builder(), "run"), ["task", "child", "done"]);
+}
+
+#[test]
+fn nested_too_deep() {
+    let mut expected = vec!["nest"; 9];
+
+    expected.push("deep");
+    assert_eq!(run(Ardaku::builder(), "nest"), expected);
+}
+
+#[test]
+fn child_out_of_fuel() {
+    // The child may only use half of its parent's fuel
+    assert_eq!(run(Ardaku::builder().fuel(100_000), "fuel"), ["done"]);
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use ardaku::Ardaku;
+
+use self::common::Logs;
+
+/// Sets a 50 ms and a 300 ms timer, then waits on both without sending any
+/// more commands
+const GUEST: &str = r#"
+(module
+  (import "daku" "ar" (func $ar (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  ;; Connect to the timer portal (channel 1)
+  (data (i32.const 0) "\10\00\00\00\10\00\00\00\00\00\00\00\00\00\00\00")
+  (data (i32.const 16) "\01\00\00\00\40\00\00\00\04\00\00\00\80\00\00\00")
+  (data (i32.const 64) "\0c\00\00\00")
+  ;; Timer commands
+  (data (i32.const 1000) "\10\00\00\00\2c\01\00\00\01\00\00\00\07\00\00\00")
+  (data (i32.const 1016) "\10\00\00\00\54\01\00\00\01\00\00\00\05\00\00\00")
+  (data (i32.const 300) "\02\00\00\00\00\00\00\00\80\f0\fa\02\00\00\00\00")
+  (data (i32.const 340) "\02\00\00\00\00\00\00\00\00\a3\e1\11\00\00\00\00")
+
+  (func (export "run")
+    (drop (call $ar (i32.const 1) (i32.const 0)))
+    (if (i32.ne (call $ar (i32.const 2) (i32.const 1000)) (i32.const 1))
+      (then unreachable))
+    (if (i32.ne (i32.load (i32.const 128)) (i32.const 7))
+      (then unreachable))
+    (if (i32.ne (call $ar (i32.const 0) (i32.const 0)) (i32.const 1))
+      (then unreachable))
+    (if (i32.ne (i32.load (i32.const 128)) (i32.const 5))
+      (then unreachable))
+    ;; Nothing left to wait on
+    (if (i32.ne (call $ar (i32.const 0) (i32.const 0)) (i32.const 0))
+      (then unreachable)))
+)
+"#;
+
+#[test]
+fn wait_on_earlier_timers() {
+    let exe = wat::parse_str(GUEST).unwrap();
+    let (system, _messages) = Logs::new();
+    let start = Instant::now();
+
+    Ardaku::default().run(system, &exe).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(300));
+}